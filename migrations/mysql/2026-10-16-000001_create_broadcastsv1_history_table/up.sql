@@ -0,0 +1,10 @@
+CREATE TABLE broadcastsv1_history (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    writer VARCHAR(64) NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY(id),
+    INDEX broadcastsv1_history_bchannel_idx (broadcaster_id, bchannel_id, id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE broadcastsv1 ADD COLUMN note VARCHAR(1000);
+ALTER TABLE broadcastsv1 ADD COLUMN ttl INTEGER;
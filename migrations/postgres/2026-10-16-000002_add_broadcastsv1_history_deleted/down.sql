@@ -0,0 +1 @@
+ALTER TABLE broadcastsv1_history DROP COLUMN deleted;
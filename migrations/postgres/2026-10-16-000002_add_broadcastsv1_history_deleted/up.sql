@@ -0,0 +1 @@
+ALTER TABLE broadcastsv1_history ADD COLUMN deleted BOOLEAN DEFAULT FALSE NOT NULL;
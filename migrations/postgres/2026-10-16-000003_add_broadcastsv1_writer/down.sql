@@ -0,0 +1 @@
+ALTER TABLE broadcastsv1 DROP COLUMN writer;
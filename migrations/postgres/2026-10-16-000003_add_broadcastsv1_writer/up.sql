@@ -0,0 +1,3 @@
+ALTER TABLE broadcastsv1 ADD COLUMN writer VARCHAR(64) DEFAULT '' NOT NULL;
+-- Only broadcasters have written broadcasts so far
+UPDATE broadcastsv1 SET writer = broadcaster_id;
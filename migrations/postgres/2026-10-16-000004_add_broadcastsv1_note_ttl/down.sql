@@ -0,0 +1,2 @@
+ALTER TABLE broadcastsv1 DROP COLUMN ttl;
+ALTER TABLE broadcastsv1 DROP COLUMN note;
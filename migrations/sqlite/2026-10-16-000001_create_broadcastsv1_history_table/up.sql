@@ -0,0 +1,10 @@
+CREATE TABLE broadcastsv1_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    writer VARCHAR(64) NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX broadcastsv1_history_bchannel_idx
+    ON broadcastsv1_history (broadcaster_id, bchannel_id, id);
@@ -0,0 +1,16 @@
+-- SQLite lacks DROP COLUMN before 3.35.0
+CREATE TABLE broadcastsv1_history_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    writer VARCHAR(64) NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+INSERT INTO broadcastsv1_history_old
+    SELECT id, broadcaster_id, bchannel_id, version, writer, created
+    FROM broadcastsv1_history;
+DROP TABLE broadcastsv1_history;
+ALTER TABLE broadcastsv1_history_old RENAME TO broadcastsv1_history;
+CREATE INDEX broadcastsv1_history_bchannel_idx
+    ON broadcastsv1_history (broadcaster_id, bchannel_id, id);
@@ -0,0 +1 @@
+ALTER TABLE broadcastsv1_history ADD COLUMN deleted BOOLEAN DEFAULT 0 NOT NULL;
@@ -0,0 +1,15 @@
+-- SQLite lacks DROP COLUMN before 3.35.0
+CREATE TABLE broadcastsv1_old (
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_updated TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    writer VARCHAR(64) DEFAULT '' NOT NULL,
+    PRIMARY KEY(broadcaster_id, bchannel_id)
+);
+INSERT INTO broadcastsv1_old
+    SELECT broadcaster_id, bchannel_id, created, last_updated, version, writer
+    FROM broadcastsv1;
+DROP TABLE broadcastsv1;
+ALTER TABLE broadcastsv1_old RENAME TO broadcastsv1;
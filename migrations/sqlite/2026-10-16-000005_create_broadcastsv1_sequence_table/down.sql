@@ -0,0 +1 @@
+DROP TABLE broadcastsv1_sequence;
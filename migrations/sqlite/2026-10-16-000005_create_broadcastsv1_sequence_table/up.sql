@@ -0,0 +1,8 @@
+-- The current change sequence number (see reserve_sequence)
+CREATE TABLE broadcastsv1_sequence (
+    id INTEGER NOT NULL,
+    sequence BIGINT NOT NULL,
+    PRIMARY KEY(id)
+);
+INSERT INTO broadcastsv1_sequence (id, sequence)
+    SELECT 1, COALESCE(MAX(id), 0) FROM broadcastsv1_history;
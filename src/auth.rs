@@ -6,23 +6,64 @@
 /// Broadcasts are id'd by 'broadcaster_id/bchannel_id'. Broadcasters can only
 /// create new broadcasts under their own broadcaster_id. Readers can read all
 /// broadcasts.
+///
+/// A broadcaster's token may be restricted to some of its bchannel_ids (or
+/// glob patterns, where "*" matches any run of characters) by configuring it
+/// as a table:
+///
+///     kinto = ["token1", { token = "token2", channels = ["settings-*-prod"] }]
+///
+/// Likewise a reader's token may be restricted to broadcast ids (or
+/// patterns), with a bare broadcaster_id granting all of its broadcasts:
+///
+///     thirdparty = [{ token = "token3", channels = ["kinto", "atmo/ads*"] }]
+///
+/// Admins (from the optional admin_auth table) can write and delete any
+/// broadcaster's broadcasts, recorded as their writer, and read all
+/// broadcasts.
+///
+/// Tokens may be configured as salted hashes rather than in plain text: the
+/// hex HMAC-SHA256 of the token keyed by ROCKET_AUTH_SALT, prefixed with
+/// "hmac-sha256:". `megaphone hash-token <token>` prints a new token's hash.
+///
+/// Presented tokens are verified by comparing their hash to every configured
+/// token's in constant time.
 use std::collections::HashMap;
 
-use rocket::config::Value;
+use ring::{constant_time, digest, hmac};
+use rocket::config::{ConfigError, RocketConfig, Value};
 use rocket::{Config, Request, State};
 
 use db::models::{Broadcaster, Reader};
 use error::{HandlerErrorKind, HandlerResult, Result};
+use validate::RESERVED_BROADCASTER_IDS;
+
+/// Prefix of hashed tokens in rocket's Config
+const HASH_PREFIX: &str = "hmac-sha256:";
 
-/// Tokens mapped to an authorized id, from rocket's Config
-type AuthToken = String;
+/// Length of a token's HMAC-SHA256
+const HASH_LEN: usize = 32;
+
+type TokenHash = [u8; HASH_LEN];
 type UserId = String;
 
+/// A token (by its HMAC-SHA256) mapped to an authorized id, from rocket's
+/// Config
+#[derive(Debug)]
+struct Credential {
+    hash: TokenHash,
+    user_id: UserId,
+    /// bchannel_ids (or for readers broadcast ids), or patterns of, the
+    /// token is restricted to, None when unrestricted
+    channels: Option<Vec<String>>,
+}
+
 /// Grouping/role of authorization
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 enum Group {
     Broadcaster,
     Reader,
+    Admin,
 }
 
 impl Group {
@@ -31,24 +72,38 @@ impl Group {
         match *self {
             Group::Broadcaster => "broadcaster_auth",
             Group::Reader => "reader_auth",
+            Group::Admin => "admin_auth",
         }
     }
 }
 
-#[derive(Debug)]
 pub struct BearerTokenAuthenticator {
-    users: HashMap<AuthToken, UserId>,
+    key: hmac::SigningKey,
+    /// Whether ROCKET_AUTH_SALT was defined
+    salted: bool,
+    credentials: Vec<Credential>,
     groups: HashMap<UserId, Group>,
 }
 
 impl BearerTokenAuthenticator {
     pub fn from_config(config: &Config) -> Result<BearerTokenAuthenticator> {
+        let salt = match config.get_str("auth_salt") {
+            Ok(salt) => Some(salt),
+            Err(ConfigError::Missing(_)) => None,
+            Err(_) => Err(format_err!("Invalid ROCKET_AUTH_SALT"))?,
+        };
         let mut authenticator = BearerTokenAuthenticator {
-            users: HashMap::new(),
+            key: hmac::SigningKey::new(&digest::SHA256, salt.unwrap_or("").as_bytes()),
+            salted: salt.is_some(),
+            credentials: Vec::new(),
             groups: HashMap::new(),
         };
         authenticator.load_auth_from_config(Group::Broadcaster, config)?;
         authenticator.load_auth_from_config(Group::Reader, config)?;
+        match config.get_table(Group::Admin.config_name()) {
+            Err(ConfigError::Missing(_)) => (),
+            _ => authenticator.load_auth_from_config(Group::Admin, config)?,
+        }
         Ok(authenticator)
     }
 
@@ -68,6 +123,15 @@ impl BearerTokenAuthenticator {
                     dupe.config_name()
                 ))?
             }
+            if group == Group::Broadcaster
+                && RESERVED_BROADCASTER_IDS.contains(&user_id.as_str())
+            {
+                Err(format_err!(
+                    "Invalid {} user: {:?} (reserved broadcaster_id)",
+                    name,
+                    user_id
+                ))?
+            }
             self.groups.insert(user_id.to_string(), group);
 
             let tokens = tokens_val.as_array().ok_or(format_err!(
@@ -83,84 +147,269 @@ impl BearerTokenAuthenticator {
     fn load_tokens(&mut self, user_id: &UserId, group: Group, tokens: &[Value]) -> Result<()> {
         let name = group.config_name();
         for element in tokens {
-            let token =
-                element
-                    .as_str()
-                    .ok_or(format_err!("Invalid {} token for: {:?}", name, user_id))?;
-            if let Some(dupe) = self.users.get(token) {
+            let (token, mut channels) = parse_token(element)
+                .ok_or(format_err!("Invalid {} token for: {:?}", name, user_id))?;
+            if group == Group::Admin && channels.is_some() {
+                Err(format_err!(
+                    "Invalid {} token for: {:?} (admins can't be restricted to channels)",
+                    name,
+                    user_id
+                ))?
+            }
+            if group == Group::Reader {
+                for channel in channels.iter_mut().flat_map(|channels| channels.iter_mut()) {
+                    if !channel.contains('/') && !channel.ends_with('*') {
+                        channel.push_str("/*");
+                    }
+                }
+            }
+            let hash = if token.starts_with(HASH_PREFIX) {
+                if !self.salted {
+                    Err(format_err!(
+                        "Invalid {} token for: {:?} (hashed tokens require ROCKET_AUTH_SALT)",
+                        name,
+                        user_id
+                    ))?
+                }
+                from_hex(&token[HASH_PREFIX.len()..])
+                    .ok_or(format_err!("Invalid {} token hash for: {:?}", name, user_id))?
+            } else {
+                hash(&self.key, token)
+            };
+            if let Some(dupe) = self.credentials.iter().find(|other| other.hash == hash) {
                 Err(format_err!(
                     "Invalid {} token for: {:?} dupe in: {:?} ({:?})",
                     name,
                     user_id,
-                    dupe,
+                    dupe.user_id,
                     token
                 ))?
             }
-            self.users.insert(token.to_string(), user_id.to_string());
+            self.credentials.push(Credential {
+                hash: hash,
+                user_id: user_id.to_string(),
+                channels: channels,
+            });
         }
         Ok(())
     }
 
-    /// Determine if Bearer token header is for an authenticated user
-    fn authenticated_user(&self, credentials: &str) -> HandlerResult<(UserId, Group)> {
-        let parts: Vec<_> = credentials.splitn(2, ' ').collect();
-        if parts.len() != 2 || parts[0].to_lowercase() != "bearer" {
-            Err(HandlerErrorKind::InvalidAuth)?
-        }
-
-        let user_id = self.users
-            .get(parts[1])
+    /// Find the Credential of a Bearer token header's token
+    fn authenticate(&self, credentials: &str) -> HandlerResult<(&Credential, Group)> {
+        let token = parse_bearer(credentials)?;
+        let credential = self
+            .find_credential(token)
             .ok_or_else(|| HandlerErrorKind::InvalidAuth)?;
         // Authenticated
-        let group = self.groups
-            .get(user_id)
+        let group = self
+            .groups
+            .get(&credential.user_id)
             .ok_or_else(|| HandlerErrorKind::InternalError)?;
-        Ok((user_id.to_string(), *group))
+        Ok((credential, *group))
+    }
+
+    /// Find a token's Credential
+    ///
+    /// Every configured token's hash is compared (in constant time), so the
+    /// time taken doesn't depend on how much of the token matched.
+    fn find_credential(&self, token: &str) -> Option<&Credential> {
+        let hash = hash(&self.key, token);
+        let mut found = None;
+        for credential in &self.credentials {
+            if constant_time::verify_slices_are_equal(&credential.hash, &hash).is_ok() {
+                found = Some(credential);
+            }
+        }
+        found
+    }
+
+    /// Authorize a reader from its Authorization header's credentials
+    pub fn authorize_reader(&self, credentials: &str) -> HandlerResult<Reader> {
+        let (credential, group) = self.authenticate(credentials)?;
+        if group == Group::Reader || group == Group::Admin {
+            // Authorized (broadcasts are filtered by the Reader's scope)
+            Ok(Reader::new(
+                credential.user_id.clone(),
+                credential.channels.clone(),
+            ))
+        } else {
+            Err(HandlerErrorKind::Unauthorized)?
+        }
     }
 }
 
-fn authenticated_user(request: &Request) -> HandlerResult<(UserId, Group)> {
+/// Parse a configured token: either the token itself or a table of the token
+/// and the channels it's restricted to
+fn parse_token(value: &Value) -> Option<(&str, Option<Vec<String>>)> {
+    match *value {
+        Value::String(ref token) => Some((token.as_str(), None)),
+        Value::Table(ref table) => {
+            let token = table.get("token")?.as_str()?;
+            let channels = match table.get("channels") {
+                Some(channels) => Some(
+                    channels
+                        .as_array()?
+                        .iter()
+                        .map(|channel| channel.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()?,
+                ),
+                None => None,
+            };
+            Some((token, channels))
+        }
+        _ => None,
+    }
+}
+
+/// Hash a new token for the config, salted with the active environment's
+/// ROCKET_AUTH_SALT
+pub fn hash_token(token: &str) -> Result<String> {
+    let config = RocketConfig::read()
+        .map_err(|e| format_err!("Invalid rocket Config: {:?}", e))?;
+    let salt = config
+        .active()
+        .get_str("auth_salt")
+        .map_err(|_| format_err!("Invalid or undefined ROCKET_AUTH_SALT"))?;
+    let key = hmac::SigningKey::new(&digest::SHA256, salt.as_bytes());
+    Ok(format!("{}{}", HASH_PREFIX, to_hex(&hash(&key, token))))
+}
+
+/// The HMAC-SHA256 of a token
+fn hash(key: &hmac::SigningKey, token: &str) -> TokenHash {
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(hmac::sign(key, token.as_bytes()).as_ref());
+    hash
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<TokenHash> {
+    if hex.len() != HASH_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; HASH_LEN];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Parse the token from an Authorization header's credentials, per RFC 6750:
+///
+///     credentials = "Bearer" 1*SP b64token
+///
+/// The scheme is case insensitive and surrounding whitespace is ignored.
+fn parse_bearer(credentials: &str) -> HandlerResult<&str> {
+    let credentials = credentials.trim_matches(|c| c == ' ' || c == '\t');
+    let (scheme, token) = match credentials.find(' ') {
+        Some(i) => (&credentials[..i], credentials[i..].trim_left_matches(' ')),
+        None => (credentials, ""),
+    };
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        Err(HandlerErrorKind::UnsupportedAuthScheme)?
+    }
+    if !is_b64token(token) {
+        Err(HandlerErrorKind::MalformedAuth)?
+    }
+    Ok(token)
+}
+
+/// Determine if a token is an RFC 6750 b64token:
+///
+///     b64token = 1*( ALPHA / DIGIT / "-" / "." / "_" / "~" / "+" / "/" ) *"="
+fn is_b64token(token: &str) -> bool {
+    let unpadded = token.trim_right_matches('=');
+    !unpadded.is_empty()
+        && unpadded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c))
+}
+
+pub fn authorized_broadcaster(request: &Request) -> HandlerResult<Broadcaster> {
     let credentials = request
         .headers()
         .get_one("Authorization")
         .ok_or_else(|| HandlerErrorKind::MissingAuth)?;
-    request
+    let authenticator = request
         .guard::<State<BearerTokenAuthenticator>>()
-        .success_or(HandlerErrorKind::InternalError)?
-        .authenticated_user(credentials)
-}
-
-pub fn authorized_broadcaster(request: &Request) -> HandlerResult<Broadcaster> {
-    let (id, group) = authenticated_user(request)?;
+        .success_or(HandlerErrorKind::InternalError)?;
+    let (credential, group) = authenticator.authenticate(credentials)?;
 
     // param should be guaranteed on the path when we're called
     let for_broadcast_id = request
         .get_param::<String>(0)
         .map_err(HandlerErrorKind::RocketError)?;
 
-    if group == Group::Broadcaster && id == for_broadcast_id {
-        // Authorized
-        Ok(Broadcaster::new(id))
+    if group == Group::Broadcaster && credential.user_id == for_broadcast_id {
+        // Authorized (bchannel_ids are authorized by the Broadcaster)
+        Ok(Broadcaster::new(
+            credential.user_id.clone(),
+            credential.channels.clone(),
+        ))
+    } else if group == Group::Admin {
+        // Authorized on behalf of the broadcaster
+        Ok(Broadcaster::admin(for_broadcast_id, credential.user_id.clone()))
     } else {
         Err(HandlerErrorKind::Unauthorized)?
     }
 }
 
 pub fn authorized_reader(request: &Request) -> HandlerResult<Reader> {
-    let (id, group) = authenticated_user(request)?;
-    if group == Group::Reader {
-        // Authorized
-        Ok(Reader::new(id))
-    } else {
-        Err(HandlerErrorKind::Unauthorized)?
-    }
+    let credentials = request
+        .headers()
+        .get_one("Authorization")
+        .ok_or_else(|| HandlerErrorKind::MissingAuth)?;
+    request
+        .guard::<State<BearerTokenAuthenticator>>()
+        .success_or(HandlerErrorKind::InternalError)?
+        .authorize_reader(credentials)
 }
 
 #[cfg(test)]
 mod test {
-    use rocket::config::{Config, Environment};
+    use ring::{digest, hmac};
+    use rocket::config::{Config, Environment, Table, Value};
+
+    use super::{
+        from_hex, hash, parse_bearer, to_hex, BearerTokenAuthenticator, Group, HASH_PREFIX,
+    };
+    use error::HandlerErrorKind;
+
+    /// The user id and group authenticated by a Bearer token header
+    fn authenticated_user(
+        authenticator: &BearerTokenAuthenticator,
+        credentials: &str,
+    ) -> Option<(String, Group)> {
+        authenticator
+            .authenticate(credentials)
+            .ok()
+            .map(|(credential, group)| (credential.user_id.clone(), group))
+    }
 
-    use super::{BearerTokenAuthenticator, Group};
+    /// Config with foo's broadcaster token hashed with the "s3cret" salt
+    fn hashed_config(salt: Option<&str>, token: &str, reader_token: &str) -> Config {
+        let key = hmac::SigningKey::new(&digest::SHA256, b"s3cret");
+        let hashed = format!("{}{}", HASH_PREFIX, to_hex(&hash(&key, token)));
+        let mut broadcaster_auth = Table::new();
+        broadcaster_auth.insert(
+            "foo".to_string(),
+            Value::Array(vec![Value::String(hashed)]),
+        );
+        let mut reader_auth = Table::new();
+        reader_auth.insert(
+            "otto".to_string(),
+            Value::Array(vec![Value::String(reader_token.to_string())]),
+        );
+        let mut builder = Config::build(Environment::Development)
+            .extra("broadcaster_auth", Value::Table(broadcaster_auth))
+            .extra("reader_auth", Value::Table(reader_auth));
+        if let Some(salt) = salt {
+            builder = builder.extra("auth_salt", salt);
+        }
+        builder.unwrap()
+    }
 
     #[test]
     fn test_basic() {
@@ -171,14 +420,21 @@ mod test {
         let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
 
         assert_eq!(
-            authenicator.authenticated_user("Bearer quux").unwrap(),
-            ("baz".to_string(), Group::Broadcaster)
+            authenticated_user(&authenicator, "Bearer quux"),
+            Some(("baz".to_string(), Group::Broadcaster))
         );
         assert_eq!(
-            authenicator.authenticated_user("Bearer push").unwrap(),
-            ("otto".to_string(), Group::Reader)
+            authenticated_user(&authenicator, "Bearer push"),
+            Some(("otto".to_string(), Group::Reader))
         );
-        assert!(authenicator.authenticated_user("Bearer mega").is_err());
+        assert!(authenticated_user(&authenicator, "Bearer mega").is_none());
+
+        assert_eq!(
+            authenicator.authorize_reader("Bearer push").unwrap().id,
+            "otto"
+        );
+        let err = authenicator.authorize_reader("Bearer quux").err().unwrap();
+        assert_eq!(*err.kind(), HandlerErrorKind::Unauthorized);
     }
 
     #[test]
@@ -207,4 +463,163 @@ mod test {
             .unwrap();
         assert!(BearerTokenAuthenticator::from_config(&config).is_err());
     }
+
+    #[test]
+    fn test_reserved_user() {
+        let config = Config::build(Environment::Development)
+            .extra("broadcaster_auth", toml!{stream = ["bar"]})
+            .extra("reader_auth", toml!{otto = ["push"]})
+            .unwrap();
+        assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_hashed() {
+        let config = hashed_config(Some("s3cret"), "bar", "push");
+        let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        assert_eq!(
+            authenticated_user(&authenicator, "Bearer bar"),
+            Some(("foo".to_string(), Group::Broadcaster))
+        );
+        assert_eq!(
+            authenticated_user(&authenicator, "Bearer push"),
+            Some(("otto".to_string(), Group::Reader))
+        );
+        let hashed = to_hex(&hash(&authenicator.key, "bar"));
+        assert!(authenticated_user(&authenicator, &format!("Bearer {}", hashed)).is_none());
+
+        // Hashed with a different salt
+        let config = hashed_config(Some("pepper"), "bar", "push");
+        let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        assert!(authenticated_user(&authenicator, "Bearer bar").is_none());
+    }
+
+    #[test]
+    fn test_hashed_invalid() {
+        // Unsalted
+        let config = hashed_config(None, "bar", "push");
+        assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+        // Dupe of the plain text token
+        let config = hashed_config(Some("s3cret"), "bar", "bar");
+        assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+
+        let config = Config::build(Environment::Development)
+            .extra("auth_salt", "s3cret")
+            .extra("broadcaster_auth", toml!{foo = ["hmac-sha256:feedface"]})
+            .extra("reader_auth", toml!{otto = ["push"]})
+            .unwrap();
+        assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let hash = [0xa5; 32];
+        assert_eq!(from_hex(&to_hex(&hash)), Some(hash));
+        assert_eq!(from_hex(&to_hex(&hash).to_uppercase()), Some(hash));
+        assert_eq!(from_hex("a5"), None);
+        assert_eq!(from_hex(&"g5".repeat(32)), None);
+        assert_eq!(from_hex(&"\u{e9}".repeat(32)), None);
+    }
+
+    #[test]
+    fn test_parse_bearer() {
+        assert_eq!(parse_bearer("Bearer foo").unwrap(), "foo");
+        assert_eq!(parse_bearer("bEaReR foo").unwrap(), "foo");
+        assert_eq!(parse_bearer(" Bearer   a-._~+/Z9== \t").unwrap(), "a-._~+/Z9==");
+        for &(credentials, ref kind) in &[
+            ("Basic Zm9vOmJhcg==", HandlerErrorKind::UnsupportedAuthScheme),
+            ("Bearerfoo", HandlerErrorKind::UnsupportedAuthScheme),
+            ("", HandlerErrorKind::UnsupportedAuthScheme),
+            ("Bearer", HandlerErrorKind::MalformedAuth),
+            ("Bearer ", HandlerErrorKind::MalformedAuth),
+            ("Bearer foo bar", HandlerErrorKind::MalformedAuth),
+            ("Bearer foo=bar", HandlerErrorKind::MalformedAuth),
+            ("Bearer ==", HandlerErrorKind::MalformedAuth),
+            ("Bearer f\u{f6}\u{f6}", HandlerErrorKind::MalformedAuth),
+        ] {
+            let err = parse_bearer(credentials).err().unwrap();
+            assert_eq!(err.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        let config = Config::build(Environment::Development)
+            .extra(
+                "broadcaster_auth",
+                toml!{foo = ["bar", { token = "baz", channels = ["quux", "ba*"] }]},
+            )
+            .extra(
+                "reader_auth",
+                toml!{otto = ["push", { token = "pull", channels = ["foo", "baz/*", "q*"] }]},
+            )
+            .unwrap();
+        let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        let reader = authenicator.authorize_reader("Bearer push").unwrap();
+        assert_eq!(reader.scope, None);
+        let reader = authenicator.authorize_reader("Bearer pull").unwrap();
+        assert_eq!(
+            reader.scope,
+            Some(vec![
+                "foo/*".to_string(),
+                "baz/*".to_string(),
+                "q*".to_string(),
+            ])
+        );
+        assert!(reader.can_read("foo/bar"));
+        assert!(!reader.can_read("foobar/baz"));
+        assert!(reader.can_read("quux/bar"));
+
+        let (credential, _) = authenicator.authenticate("Bearer bar").unwrap();
+        assert_eq!(credential.channels, None);
+        let (credential, group) = authenicator.authenticate("Bearer baz").unwrap();
+        assert_eq!(credential.user_id, "foo");
+        assert_eq!(group, Group::Broadcaster);
+        assert_eq!(
+            credential.channels,
+            Some(vec!["quux".to_string(), "ba*".to_string()])
+        );
+
+        for broadcaster_auth in &[
+            toml!{foo = [{ channels = ["quux"] }]},
+            toml!{foo = [{ token = "baz", channels = "quux" }]},
+            toml!{foo = [{ token = "baz", channels = [1] }]},
+        ] {
+            let config = Config::build(Environment::Development)
+                .extra("broadcaster_auth", broadcaster_auth.clone())
+                .extra("reader_auth", toml!{otto = ["push"]})
+                .unwrap();
+            assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_admin() {
+        let config = Config::build(Environment::Development)
+            .extra("broadcaster_auth", toml!{foo = ["bar"]})
+            .extra("reader_auth", toml!{otto = ["push"]})
+            .extra("admin_auth", toml!{root = ["toor"]})
+            .unwrap();
+        let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        assert_eq!(
+            authenticated_user(&authenicator, "Bearer toor"),
+            Some(("root".to_string(), Group::Admin))
+        );
+        let reader = authenicator.authorize_reader("Bearer toor").unwrap();
+        assert_eq!(reader.id, "root");
+        assert_eq!(reader.scope, None);
+
+        for admin_auth in &[
+            toml!{root = [{ token = "toor", channels = ["quux"] }]},
+            toml!{foo = ["toor"]},
+            toml!{root = ["bar"]},
+        ] {
+            let config = Config::build(Environment::Development)
+                .extra("broadcaster_auth", toml!{foo = ["bar"]})
+                .extra("reader_auth", toml!{otto = ["push"]})
+                .extra("admin_auth", admin_auth.clone())
+                .unwrap();
+            assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+        }
+    }
 }
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use failure::err_msg;
+use rocket::Config;
+use serde_json;
+
+use super::models::{Broadcast, HistoryEntry, NewVersion, Since};
+use super::BroadcastStore;
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+/// The store's contents, also the format of its snapshot file
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Data {
+    /// Broadcasts keyed by Broadcast::id
+    #[serde(default)]
+    broadcasts: BTreeMap<String, Broadcast>,
+    /// Every broadcast's history, oldest first
+    #[serde(default)]
+    history: VecDeque<HistoryEntry>,
+}
+
+impl Data {
+    fn next_history_id(&self) -> i64 {
+        self.history.back().map_or(1, |entry| entry.id + 1)
+    }
+
+    /// Drop the oldest history entries beyond max
+    fn truncate_history(&mut self, max: usize) {
+        while self.history.len() > max {
+            self.history.pop_front();
+        }
+    }
+}
+
+/// A BroadcastStore held in memory
+///
+/// Optionally loaded from and periodically persisted to a JSON snapshot
+/// file, which is also written when the store is dropped. Intended for
+/// development and testing.
+pub struct MemoryStore {
+    data: Arc<RwLock<Data>>,
+    /// Whether data has changed since the last snapshot
+    dirty: Arc<AtomicBool>,
+    /// The snapshot file's path (when persisted), locked while it's written
+    snapshot: Option<Arc<Mutex<PathBuf>>>,
+    /// The number of history entries kept
+    history_size: usize,
+}
+
+impl MemoryStore {
+    /// Create the store, loading its snapshot file if one exists
+    ///
+    /// database_url is of the form "memory://[<snapshot path>]". Without a
+    /// snapshot path nothing is persisted. Snapshots are written every
+    /// database_snapshot_interval seconds (default 10) when data has changed.
+    ///
+    /// Only the newest database_history_size (default 10000) history entries
+    /// are kept, older ones are dropped.
+    pub fn from_config(config: &Config, database_url: &str) -> Result<MemoryStore> {
+        let history_size = config.get_int("database_history_size").unwrap_or(10_000);
+        if history_size <= 0 {
+            Err(err_msg("Invalid ROCKET_DATABASE_HISTORY_SIZE"))?
+        }
+        let history_size = history_size as usize;
+        let path = &database_url["memory://".len()..];
+        if path.is_empty() {
+            return Ok(MemoryStore::new(Data::default(), history_size));
+        }
+
+        let path = PathBuf::from(path);
+        let data = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            Data::default()
+        };
+        let interval = config.get_int("database_snapshot_interval").unwrap_or(10);
+        if interval <= 0 {
+            Err(err_msg("Invalid ROCKET_DATABASE_SNAPSHOT_INTERVAL"))?
+        }
+        let mut store = MemoryStore::new(data, history_size);
+        store.snapshot = Some(Arc::new(Mutex::new(path)));
+        store.spawn_snapshotter(Duration::from_secs(interval as u64));
+        Ok(store)
+    }
+
+    fn new(mut data: Data, history_size: usize) -> MemoryStore {
+        data.truncate_history(history_size);
+        MemoryStore {
+            data: Arc::new(RwLock::new(data)),
+            dirty: Arc::new(AtomicBool::new(false)),
+            snapshot: None,
+            history_size: history_size,
+        }
+    }
+
+    /// Periodically write the snapshot file in a background thread, until
+    /// the store is dropped
+    fn spawn_snapshotter(&self, interval: Duration) {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot.clone(),
+            None => return,
+        };
+        let data = Arc::downgrade(&self.data);
+        let dirty = self.dirty.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match data.upgrade() {
+                Some(data) => flush(&data, &dirty, &snapshot),
+                None => return,
+            }
+        });
+    }
+
+    fn read(&self) -> HandlerResult<RwLockReadGuard<Data>> {
+        Ok(self
+            .data
+            .read()
+            .map_err(|_| HandlerErrorKind::InternalError)?)
+    }
+
+    fn write(&self) -> HandlerResult<RwLockWriteGuard<Data>> {
+        let data = self
+            .data
+            .write()
+            .map_err(|_| HandlerErrorKind::InternalError)?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(data)
+    }
+}
+
+impl Drop for MemoryStore {
+    /// Write the changes since the last snapshot
+    fn drop(&mut self) {
+        if let Some(ref snapshot) = self.snapshot {
+            flush(&self.data, &self.dirty, snapshot);
+        }
+    }
+}
+
+/// Write the snapshot file if data has changed since the last one
+fn flush(data: &RwLock<Data>, dirty: &AtomicBool, snapshot: &Mutex<PathBuf>) {
+    let path = snapshot
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if !dirty.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if let Err(e) = write_snapshot(data, &path) {
+        error!("Error writing snapshot {:?}: {}", *path, e);
+        dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Atomically replace the snapshot file with the current data
+fn write_snapshot(data: &RwLock<Data>, path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let data = data.read().map_err(|_| err_msg("Poisoned data lock"))?;
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &*data)?;
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn key(broadcaster_id: &str, bchannel_id: &str) -> String {
+    format!("{}/{}", broadcaster_id, bchannel_id)
+}
+
+/// Upsert a broadcast into the locked data
+fn upsert_version(data: &mut Data, new: &NewVersion) -> HandlerResult<bool> {
+    let now = Utc::now().naive_utc();
+    let (created, created_at) = match data
+        .broadcasts
+        .get(&key(new.broadcaster_id, new.bchannel_id))
+    {
+        Some(current) if new.precondition_holds(Some(current.version.as_str())) => {
+            (false, current.created)
+        }
+        None if new.precondition_holds(None) => (true, now),
+        _ => Err(HandlerErrorKind::PreconditionFailed)?,
+    };
+    let broadcast = Broadcast {
+        broadcaster_id: new.broadcaster_id.to_string(),
+        bchannel_id: new.bchannel_id.to_string(),
+        version: new.version.to_string(),
+        created: created_at,
+        last_updated: now,
+        writer: new.writer.to_string(),
+        note: new.note.map(str::to_string),
+        ttl: new.ttl,
+    };
+    let id = data.next_history_id();
+    data.history.push_back(HistoryEntry {
+        id: id,
+        broadcaster_id: broadcast.broadcaster_id.clone(),
+        bchannel_id: broadcast.bchannel_id.clone(),
+        version: broadcast.version.clone(),
+        writer: new.writer.to_string(),
+        created: now,
+        deleted: false,
+    });
+    data.broadcasts.insert(broadcast.id(), broadcast);
+    Ok(created)
+}
+
+impl BroadcastStore for MemoryStore {
+    fn upsert(&self, new: &NewVersion) -> HandlerResult<bool> {
+        let mut data = self.write()?;
+        let created = upsert_version(&mut data, new)?;
+        data.truncate_history(self.history_size);
+        Ok(created)
+    }
+
+    fn upsert_many(&self, new: &[NewVersion]) -> HandlerResult<Vec<bool>> {
+        let mut data = self.write()?;
+        // Check every precondition before writing anything
+        for new in new {
+            let current = data
+                .broadcasts
+                .get(&key(new.broadcaster_id, new.bchannel_id));
+            if !new.precondition_holds(current.map(|current| current.version.as_str())) {
+                Err(HandlerErrorKind::PreconditionFailed)?
+            }
+        }
+        let created = new
+            .iter()
+            .map(|new| upsert_version(&mut data, new))
+            .collect::<HandlerResult<Vec<_>>>()?;
+        data.truncate_history(self.history_size);
+        Ok(created)
+    }
+
+    fn read_all(&self) -> HandlerResult<Vec<Broadcast>> {
+        Ok(self.read()?.broadcasts.values().cloned().collect())
+    }
+
+    fn read_broadcaster(&self, broadcaster_id: &str) -> HandlerResult<Vec<Broadcast>> {
+        Ok(self
+            .read()?
+            .broadcasts
+            .values()
+            .filter(|broadcast| broadcast.broadcaster_id == broadcaster_id)
+            .cloned()
+            .collect())
+    }
+
+    fn read_one(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+    ) -> HandlerResult<Option<Broadcast>> {
+        Ok(self
+            .read()?
+            .broadcasts
+            .get(&key(broadcaster_id, bchannel_id))
+            .cloned())
+    }
+
+    fn read_history(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        limit: i64,
+        before: Option<i64>,
+    ) -> HandlerResult<Vec<HistoryEntry>> {
+        let before = before.unwrap_or(i64::max_value());
+        Ok(self
+            .read()?
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| {
+                entry.id < before
+                    && entry.broadcaster_id == broadcaster_id
+                    && entry.bchannel_id == bchannel_id
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn read_sequence(&self) -> HandlerResult<i64> {
+        Ok(self.read()?.next_history_id() - 1)
+    }
+
+    fn read_changes(&self, since: &Since, until: i64) -> HandlerResult<Vec<HistoryEntry>> {
+        Ok(self
+            .read()?
+            .history
+            .iter()
+            .filter(|entry| {
+                entry.id <= until
+                    && match *since {
+                        Since::Sequence(sequence) => entry.id > sequence,
+                        Since::Timestamp(timestamp) => entry.created > timestamp,
+                    }
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&self, writer: &str, broadcaster_id: &str, bchannel_id: &str) -> HandlerResult<bool> {
+        let mut data = self.write()?;
+        let broadcast = match data.broadcasts.remove(&key(broadcaster_id, bchannel_id)) {
+            Some(broadcast) => broadcast,
+            None => return Ok(false),
+        };
+        let id = data.next_history_id();
+        data.history.push_back(HistoryEntry {
+            id: id,
+            broadcaster_id: broadcast.broadcaster_id,
+            bchannel_id: broadcast.bchannel_id,
+            version: broadcast.version,
+            writer: writer.to_string(),
+            created: Utc::now().naive_utc(),
+            deleted: true,
+        });
+        data.truncate_history(self.history_size);
+        Ok(true)
+    }
+
+    fn health_check(&self) -> Result<()> {
+        self.data
+            .read()
+            .map_err(|_| err_msg("Poisoned data lock"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use rocket::config::{Config, Environment};
+
+    use super::MemoryStore;
+    use db::models::NewVersion;
+    use db::BroadcastStore;
+
+    fn upsert(store: &MemoryStore, broadcaster_id: &str, version: &str) -> bool {
+        store
+            .upsert(&NewVersion {
+                writer: broadcaster_id,
+                broadcaster_id: broadcaster_id,
+                bchannel_id: "bar",
+                version: version,
+                note: None,
+                ttl: None,
+                if_match: &[],
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = env::temp_dir().join("megaphone_test_snapshot.json");
+        let _ = fs::remove_file(&path);
+        let database_url = format!("memory://{}", path.display());
+        let config = Config::build(Environment::Development).unwrap();
+
+        let store = MemoryStore::from_config(&config, &database_url).unwrap();
+        assert!(upsert(&store, "foo", "v1"));
+        assert!(!upsert(&store, "foo", "v2"));
+        assert!(upsert(&store, "baz", "v0"));
+        assert!(store.delete("baz", "baz", "bar").unwrap());
+        // Written when dropped
+        drop(store);
+
+        let store = MemoryStore::from_config(&config, &database_url).unwrap();
+        let broadcasts = store.read_all().unwrap();
+        assert_eq!(broadcasts.len(), 1);
+        assert_eq!(broadcasts[0].id(), "foo/bar");
+        assert_eq!(broadcasts[0].version, "v2");
+        let history = store.read_history("foo", "bar", 10, None).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].version, "v2");
+        assert_eq!(history[1].version, "v1");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_snapshot() {
+        let config = Config::build(Environment::Development).unwrap();
+        let store = MemoryStore::from_config(&config, "memory://").unwrap();
+        assert!(store.read_one("foo", "bar").unwrap().is_none());
+        assert!(!store.delete("foo", "foo", "bar").unwrap());
+        assert!(upsert(&store, "foo", "v1"));
+        assert_eq!(store.read_one("foo", "bar").unwrap().unwrap().version, "v1");
+    }
+    #[test]
+    fn test_history_size() {
+        let config = Config::build(Environment::Development)
+            .extra("database_history_size", 2)
+            .unwrap();
+        let store = MemoryStore::from_config(&config, "memory://").unwrap();
+        assert!(upsert(&store, "foo", "v1"));
+        assert!(!upsert(&store, "foo", "v2"));
+        assert!(!upsert(&store, "foo", "v3"));
+        let history = store.read_history("foo", "bar", 10, None).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].version, "v3");
+        assert_eq!(history[1].version, "v2");
+        assert_eq!(store.read_sequence().unwrap(), 3);
+    }
+}
@@ -1,83 +1,183 @@
 pub mod models;
 pub mod schema;
+#[macro_use]
+mod queries;
+mod memory;
+mod mysql;
+mod postgres;
+mod sqlite;
 
 use std::ops::Deref;
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
+use diesel::connection::SimpleConnection;
 use diesel::Connection;
-use diesel::mysql::MysqlConnection;
-use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error, Pool, PooledConnection};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error, Pool};
 use failure::err_msg;
 
-use rocket::http::Status;
 use rocket::request::{self, FromRequest};
-use rocket::{Config, Outcome, Request, State};
+use rocket::{Config, Request, State};
 
-use error::Result;
+use self::models::{Broadcast, HistoryEntry, NewVersion, Since};
+use error::{HandlerResult, Result};
 
-pub type MysqlPool = Pool<ConnectionManager<MysqlConnection>>;
-
-embed_migrations!();
+/// Storage backend for broadcasts
+///
+/// Implementations manage their own connections (e.g. a connection pool) so
+/// the HTTP layer never knows which database it's talking to.
+pub trait BroadcastStore: Send + Sync {
+    /// Create or update a broadcast's version, recording it in the
+    /// broadcast's history
+    ///
+    /// The If-Match precondition is checked atomically with the write,
+    /// Err(PreconditionFailed) if it doesn't hold.
+    ///
+    /// Returns Ok(true) if the broadcast was created, Ok(false) if an existing
+    /// broadcast was modified.
+    fn upsert(&self, new: &NewVersion) -> HandlerResult<bool>;
+
+    /// Upsert multiple broadcasts atomically: either every write is applied
+    /// or none are
+    ///
+    /// Returns whether each broadcast was created, in order.
+    fn upsert_many(&self, new: &[NewVersion]) -> HandlerResult<Vec<bool>>;
+
+    /// Read every broadcast
+    fn read_all(&self) -> HandlerResult<Vec<Broadcast>>;
+
+    /// Read every broadcast of a broadcaster
+    fn read_broadcaster(&self, broadcaster_id: &str) -> HandlerResult<Vec<Broadcast>>;
+
+    /// Read a single broadcast, if it exists
+    fn read_one(&self, broadcaster_id: &str, bchannel_id: &str)
+        -> HandlerResult<Option<Broadcast>>;
+
+    /// Read a page of a broadcast's history, newest first
+    ///
+    /// Only entries with an id less than before (when specified) are
+    /// included.
+    fn read_history(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        limit: i64,
+        before: Option<i64>,
+    ) -> HandlerResult<Vec<HistoryEntry>>;
+
+    /// Read the current change sequence number: the newest history entry's
+    /// id (or 0)
+    ///
+    /// Sequence numbers are committed in increasing order (possibly with
+    /// gaps), so no change is committed at or below one already read.
+    fn read_sequence(&self) -> HandlerResult<i64>;
+
+    /// Read every history entry since a point in the history up to (and
+    /// including) the until sequence number, oldest first
+    fn read_changes(&self, since: &Since, until: i64) -> HandlerResult<Vec<HistoryEntry>>;
+
+    /// Delete a broadcast, recording its deletion in the broadcast's history
+    /// as written by the writer's user id
+    ///
+    /// Returns Ok(false) if the broadcast did not exist.
+    fn delete(&self, writer: &str, broadcaster_id: &str, bchannel_id: &str) -> HandlerResult<bool>;
+
+    /// Determine if the backend is available (for __heartbeat__)
+    fn health_check(&self) -> Result<()>;
+}
 
-/// Run the diesel embedded migrations
+/// Create the BroadcastStore selected by the ROCKET_DATABASE_URL's scheme
 ///
-/// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
-/// begin_test_transaction during tests. So this runs on its own separate conn.
-pub fn run_embedded_migrations(config: &Config) -> Result<()> {
+/// Any embedded migrations for the backend are run.
+pub fn store_from_config(config: &Config) -> Result<Box<BroadcastStore>> {
     let database_url = config
         .get_str("database_url")
-        .map_err(|_| err_msg("Invalid or undefined ROCKET_DATABASE_URL"))?
-        .to_string();
-    let conn = MysqlConnection::establish(&database_url)?;
-    Ok(embedded_migrations::run(&conn)?)
+        .map_err(|_| err_msg("Invalid or undefined ROCKET_DATABASE_URL"))?;
+    let scheme = database_url.splitn(2, "://").next().unwrap_or("");
+    match scheme {
+        "memory" => Ok(Box::new(memory::MemoryStore::from_config(
+            config,
+            database_url,
+        )?)),
+        "mysql" => Ok(Box::new(mysql::MysqlStore::from_config(
+            config,
+            database_url,
+        )?)),
+        "postgres" | "postgresql" => Ok(Box::new(postgres::PostgresStore::from_config(
+            config,
+            database_url,
+        )?)),
+        "sqlite" => Ok(Box::new(sqlite::SqliteStore::from_config(
+            config,
+            database_url,
+        )?)),
+        _ => Err(format_err!(
+            "Unsupported ROCKET_DATABASE_URL scheme: {:?}",
+            scheme
+        )),
+    }
 }
 
-pub fn pool_from_config(config: &Config) -> Result<MysqlPool> {
-    let database_url = config
-        .get_str("database_url")
-        .map_err(|_| err_msg("Invalid or undefined ROCKET_DATABASE_URL"))?
-        .to_string();
+/// Build a diesel connection pool for the database_url
+///
+/// Each connection's session is first setup by the session_sql statements.
+fn pool_from_config<C>(
+    config: &Config,
+    database_url: &str,
+    session_sql: &'static str,
+) -> Result<Pool<ConnectionManager<C>>>
+where
+    C: Connection + Send + 'static,
+{
     let max_size = config.get_int("database_pool_max_size").unwrap_or(10) as u32;
     let use_test_transactions = config
         .get_bool("database_use_test_transactions")
         .unwrap_or(false);
 
-    let manager = ConnectionManager::<MysqlConnection>::new(database_url);
-    let mut builder = Pool::builder().max_size(max_size);
-    if use_test_transactions {
-        builder = builder.connection_customizer(Box::new(TestTransactionCustomizer));
-    }
-    Ok(builder.build(manager)?)
+    let manager = ConnectionManager::<C>::new(database_url);
+    Ok(Pool::builder()
+        .max_size(max_size)
+        .connection_customizer(Box::new(SessionCustomizer {
+            session_sql: session_sql,
+            use_test_transactions: use_test_transactions,
+        }))
+        .build(manager)?)
 }
 
-pub struct Conn(pub PooledConnection<ConnectionManager<MysqlConnection>>);
+/// The managed BroadcastStore
+pub struct Store<'r>(State<'r, Arc<BroadcastStore>>);
 
-impl Deref for Conn {
-    type Target = MysqlConnection;
+impl<'r> Deref for Store<'r> {
+    type Target = BroadcastStore;
 
     #[inline(always)]
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &**self.0
     }
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for Conn {
+impl<'a, 'r> FromRequest<'a, 'r> for Store<'r> {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
-        let pool = request.guard::<State<MysqlPool>>()?;
-        match pool.get() {
-            Ok(conn) => Outcome::Success(Conn(conn)),
-            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
-        }
+        request.guard::<State<Arc<BroadcastStore>>>().map(Store)
     }
 }
 
+/// Sets up new pooled connections' sessions, beginning a test transaction
+/// on them during tests
 #[derive(Debug)]
-struct TestTransactionCustomizer;
+struct SessionCustomizer {
+    session_sql: &'static str,
+    use_test_transactions: bool,
+}
 
-impl CustomizeConnection<MysqlConnection, Error> for TestTransactionCustomizer {
-    fn on_acquire(&self, conn: &mut MysqlConnection) -> StdResult<(), Error> {
-        conn.begin_test_transaction().map_err(Error::QueryError)
+impl<C: Connection> CustomizeConnection<C, Error> for SessionCustomizer {
+    fn on_acquire(&self, conn: &mut C) -> StdResult<(), Error> {
+        conn.batch_execute(self.session_sql).map_err(Error::QueryError)?;
+        if self.use_test_transactions {
+            conn.begin_test_transaction().map_err(Error::QueryError)?;
+        }
+        Ok(())
     }
 }
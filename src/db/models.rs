@@ -1,19 +1,24 @@
 use std::collections::HashMap;
 
-use diesel::mysql::MysqlConnection;
-use diesel::sql_types::Text;
-use diesel::{sql_query, QueryDsl, RunQueryDsl};
-use failure::ResultExt;
+use chrono::{DateTime, NaiveDateTime};
 
-use super::schema::broadcastsv1;
+use super::BroadcastStore;
 use error::{HandlerErrorKind, HandlerResult};
+use notify::matches;
 
-#[derive(Debug, Queryable, Insertable)]
-#[table_name = "broadcastsv1"]
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
 pub struct Broadcast {
     pub broadcaster_id: String,
     pub bchannel_id: String,
     pub version: String,
+    pub created: NaiveDateTime,
+    pub last_updated: NaiveDateTime,
+    /// User id of the last writer
+    pub writer: String,
+    /// The last writer's note describing the change
+    pub note: Option<String>,
+    /// Seconds readers may consider the version current for
+    pub ttl: Option<i32>,
 }
 
 impl Broadcast {
@@ -22,14 +27,157 @@ impl Broadcast {
     }
 }
 
+/// A broadcast's version and metadata, for full format responses
+#[derive(Debug, Serialize)]
+pub struct BroadcastInfo {
+    pub version: String,
+    pub created: NaiveDateTime,
+    pub last_updated: NaiveDateTime,
+    /// User id of the last writer
+    pub writer: String,
+    pub note: Option<String>,
+    pub ttl: Option<i32>,
+}
+
+impl From<Broadcast> for BroadcastInfo {
+    fn from(broadcast: Broadcast) -> BroadcastInfo {
+        BroadcastInfo {
+            version: broadcast.version,
+            created: broadcast.created,
+            last_updated: broadcast.last_updated,
+            writer: broadcast.writer,
+            note: broadcast.note,
+            ttl: broadcast.ttl,
+        }
+    }
+}
+
+/// A broadcaster's new version, optionally with metadata
+#[derive(Debug, Deserialize)]
+pub struct VersionData {
+    pub version: String,
+    /// Seconds readers may consider the version current for
+    pub ttl: Option<i32>,
+    /// A human readable note describing the change
+    pub note: Option<String>,
+}
+
+impl VersionData {
+    pub fn new(version: String) -> VersionData {
+        VersionData {
+            version: version,
+            ttl: None,
+            note: None,
+        }
+    }
+}
+
+/// A new version of a broadcast to be written
+pub struct NewVersion<'a> {
+    /// User id of the writer
+    pub writer: &'a str,
+    pub broadcaster_id: &'a str,
+    pub bchannel_id: &'a str,
+    pub version: &'a str,
+    pub note: Option<&'a str>,
+    pub ttl: Option<i32>,
+    /// Unquoted If-Match ETags (a broadcast's ETag is its version). The
+    /// write is unconditional when empty
+    pub if_match: &'a [String],
+}
+
+impl<'a> NewVersion<'a> {
+    /// Determine if the If-Match precondition holds for the broadcast's
+    /// current version
+    pub fn precondition_holds(&self, current: Option<&str>) -> bool {
+        if self.if_match.is_empty() {
+            return true;
+        }
+        match current {
+            Some(current) => self.if_match.iter().any(|tag| tag == "*" || tag == current),
+            None => false,
+        }
+    }
+}
+
+/// Number of history entries read at once when searching a broadcast's
+/// history
+const HISTORY_PAGE_SIZE: i64 = 100;
+
+/// A version previously broadcast
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub broadcaster_id: String,
+    pub bchannel_id: String,
+    pub version: String,
+    /// User id of the broadcast's writer
+    pub writer: String,
+    pub created: NaiveDateTime,
+    /// Whether the broadcast (of version) was deleted
+    pub deleted: bool,
+}
+
+/// A point in the broadcasts' history to read subsequent changes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Since {
+    /// A change sequence number (HistoryEntry id)
+    Sequence(i64),
+    Timestamp(NaiveDateTime),
+}
+
+impl Since {
+    /// Parse a change sequence number or an RFC 3339 timestamp
+    pub fn parse(since: &str) -> Option<Since> {
+        if let Ok(sequence) = since.parse() {
+            return Some(Since::Sequence(sequence));
+        }
+        DateTime::parse_from_rfc3339(since)
+            .ok()
+            .map(|timestamp| Since::Timestamp(timestamp.naive_utc()))
+    }
+}
+
 /// An authorized broadcaster
 pub struct Broadcaster {
     pub id: String,
+    /// User id of the writer of changes: the broadcaster or an admin acting
+    /// on its behalf
+    pub writer: String,
+    /// bchannel_ids (or glob patterns) the broadcaster is restricted to, None
+    /// when unrestricted
+    pub channels: Option<Vec<String>>,
 }
 
 impl Broadcaster {
-    pub fn new(id: String) -> Broadcaster {
-        Broadcaster { id: id }
+    pub fn new(id: String, channels: Option<Vec<String>>) -> Broadcaster {
+        Broadcaster {
+            writer: id.clone(),
+            id: id,
+            channels: channels,
+        }
+    }
+
+    /// An admin acting on behalf of a broadcaster
+    pub fn admin(id: String, admin_id: String) -> Broadcaster {
+        Broadcaster {
+            id: id,
+            writer: admin_id,
+            channels: None,
+        }
+    }
+
+    /// Ensure the broadcaster is granted a bchannel
+    ///
+    /// Returns Err(Unauthorized) if it's outside of the broadcaster's
+    /// channels.
+    fn authorize(&self, bchannel_id: &str) -> HandlerResult<()> {
+        if let Some(ref channels) = self.channels {
+            if !channels.iter().any(|pattern| matches(pattern, bchannel_id)) {
+                Err(HandlerErrorKind::Unauthorized)?
+            }
+        }
+        Ok(())
     }
 
     /// Broadcast a new version
@@ -43,48 +191,287 @@ impl Broadcaster {
     ///
     /// Ok(false) if this Broadcast had an existing version that was
     /// successfully modified to the new version.
+    ///
+    /// Err(PreconditionFailed) if if_match (unquoted ETags) is non empty and
+    /// doesn't match the current version.
+    ///
+    /// Err(Unauthorized) if the bchannel is outside of the broadcaster's
+    /// channels.
     pub fn broadcast_new_version(
         self,
-        conn: &MysqlConnection,
+        store: &BroadcastStore,
         bchannel_id: String,
-        version: String,
+        data: VersionData,
+        if_match: &[String],
     ) -> HandlerResult<bool> {
-        let affected_rows = sql_query(include_str!("upsert_broadcast.sql"))
-            .bind::<Text, _>(&self.id)
-            .bind::<Text, _>(&bchannel_id)
-            .bind::<Text, _>(&version)
-            .bind::<Text, _>(&version)
-            .execute(conn)
-            .context(HandlerErrorKind::DBError)?;
-        Ok(affected_rows == 1)
+        self.authorize(&bchannel_id)?;
+        store.upsert(&NewVersion {
+            writer: &self.writer,
+            broadcaster_id: &self.id,
+            bchannel_id: &bchannel_id,
+            version: &data.version,
+            note: data.note.as_ref().map(String::as_str),
+            ttl: data.ttl,
+            if_match: if_match,
+        })
+    }
+
+    /// Broadcast new versions of multiple bchannels in a single transaction
+    ///
+    /// Returns whether each broadcast (keyed by bchannel_id) was created, or
+    /// Err(HandlerError) if none were written.
+    pub fn broadcast_new_versions(
+        self,
+        store: &BroadcastStore,
+        versions: &HashMap<String, String>,
+    ) -> HandlerResult<HashMap<String, bool>> {
+        for bchannel_id in versions.keys() {
+            self.authorize(bchannel_id)?;
+        }
+        let new: Vec<_> = versions
+            .iter()
+            .map(|(bchannel_id, version)| NewVersion {
+                writer: &self.writer,
+                broadcaster_id: &self.id,
+                bchannel_id: bchannel_id,
+                version: version,
+                note: None,
+                ttl: None,
+                if_match: &[],
+            })
+            .collect();
+        let created = store.upsert_many(&new)?;
+        Ok(versions.keys().cloned().zip(created).collect())
+    }
+
+    /// Delete a broadcast
+    ///
+    /// Returns Err(NotFound) if the broadcast doesn't exist.
+    pub fn delete_broadcast(
+        self,
+        store: &BroadcastStore,
+        bchannel_id: String,
+    ) -> HandlerResult<()> {
+        self.authorize(&bchannel_id)?;
+        if store.delete(&self.writer, &self.id, &bchannel_id)? {
+            Ok(())
+        } else {
+            Err(HandlerErrorKind::NotFound)?
+        }
+    }
+
+    /// Roll a broadcast back to a version from its history
+    ///
+    /// Restores the most recent version differing from the current one, or
+    /// the version of the history entry `to` when specified.
+    ///
+    /// Returns the restored version. Err(NotFound) if the broadcast or the
+    /// version to restore doesn't exist, Err(PreconditionFailed) if the
+    /// broadcast was concurrently modified.
+    pub fn rollback(
+        self,
+        store: &BroadcastStore,
+        bchannel_id: String,
+        to: Option<i64>,
+    ) -> HandlerResult<String> {
+        self.authorize(&bchannel_id)?;
+        let current = store
+            .read_one(&self.id, &bchannel_id)?
+            .ok_or(HandlerErrorKind::NotFound)?;
+        let version = match to {
+            Some(id) => {
+                let before = id.checked_add(1).ok_or(HandlerErrorKind::NotFound)?;
+                let entry = store
+                    .read_history(&self.id, &bchannel_id, 1, Some(before))?
+                    .pop()
+                    .ok_or(HandlerErrorKind::NotFound)?;
+                if entry.id != id {
+                    Err(HandlerErrorKind::NotFound)?
+                }
+                entry.version
+            }
+            None => previous_version(store, &current)?,
+        };
+        store.upsert(&NewVersion {
+            writer: &self.writer,
+            broadcaster_id: &self.id,
+            bchannel_id: &bchannel_id,
+            version: &version,
+            note: None,
+            ttl: None,
+            // Fail rather than clobber a concurrent write
+            if_match: &[current.version.clone()],
+        })?;
+        Ok(version)
+    }
+}
+
+/// Find the most recent version in a broadcast's history differing from its
+/// current version
+fn previous_version(store: &BroadcastStore, current: &Broadcast) -> HandlerResult<String> {
+    let mut before = None;
+    loop {
+        let page = store.read_history(
+            &current.broadcaster_id,
+            &current.bchannel_id,
+            HISTORY_PAGE_SIZE,
+            before,
+        )?;
+        if let Some(entry) = page
+            .iter()
+            .find(|entry| !entry.deleted && entry.version != current.version)
+        {
+            return Ok(entry.version.clone());
+        }
+        match page.last() {
+            Some(entry) if page.len() as i64 == HISTORY_PAGE_SIZE => before = Some(entry.id),
+            _ => Err(HandlerErrorKind::NotFound)?,
+        }
     }
 }
 
 /// An authorized reader of broadcasts
+#[derive(Clone)]
 pub struct Reader {
     pub id: String,
+    /// Broadcast ids (or glob patterns) the reader is restricted to, None
+    /// when unrestricted
+    pub scope: Option<Vec<String>>,
 }
 
 impl Reader {
-    pub fn new(id: String) -> Reader {
-        Reader { id: id }
+    pub fn new(id: String, scope: Option<Vec<String>>) -> Reader {
+        Reader {
+            id: id,
+            scope: scope,
+        }
+    }
+
+    /// Determine if a broadcast is within the reader's scope
+    pub fn can_read(&self, id: &str) -> bool {
+        match self.scope {
+            Some(ref scope) => scope.iter().any(|pattern| matches(pattern, id)),
+            None => true,
+        }
+    }
+
+    /// Filter out the broadcasts outside of the reader's scope
+    fn in_scope(&self, mut broadcasts: Vec<Broadcast>) -> Vec<Broadcast> {
+        broadcasts.retain(|bcast| self.can_read(&bcast.id()));
+        broadcasts
     }
 
     pub fn read_broadcasts(
         &self,
-        conn: &MysqlConnection,
+        store: &BroadcastStore,
     ) -> HandlerResult<HashMap<String, String>> {
         // flatten into HashMap FromIterator<(K, V)>
-        Ok(broadcastsv1::table
-            .select((
-                broadcastsv1::broadcaster_id,
-                broadcastsv1::bchannel_id,
-                broadcastsv1::version,
-            ))
-            .load::<Broadcast>(conn)
-            .context(HandlerErrorKind::DBError)?
+        Ok(self
+            .in_scope(store.read_all()?)
             .into_iter()
             .map(|bcast| (bcast.id(), bcast.version))
             .collect())
     }
+
+    /// Read every broadcast with its metadata
+    pub fn read_broadcasts_full(
+        &self,
+        store: &BroadcastStore,
+    ) -> HandlerResult<HashMap<String, BroadcastInfo>> {
+        Ok(self
+            .in_scope(store.read_all()?)
+            .into_iter()
+            .map(|bcast| (bcast.id(), bcast.into()))
+            .collect())
+    }
+
+    /// Read every broadcast of a broadcaster
+    pub fn read_broadcaster(
+        &self,
+        store: &BroadcastStore,
+        broadcaster_id: &str,
+    ) -> HandlerResult<HashMap<String, String>> {
+        Ok(self
+            .in_scope(store.read_broadcaster(broadcaster_id)?)
+            .into_iter()
+            .map(|bcast| (bcast.id(), bcast.version))
+            .collect())
+    }
+
+    /// Read every broadcast of a broadcaster with its metadata
+    pub fn read_broadcaster_full(
+        &self,
+        store: &BroadcastStore,
+        broadcaster_id: &str,
+    ) -> HandlerResult<HashMap<String, BroadcastInfo>> {
+        Ok(self
+            .in_scope(store.read_broadcaster(broadcaster_id)?)
+            .into_iter()
+            .map(|bcast| (bcast.id(), bcast.into()))
+            .collect())
+    }
+
+    /// Read a single broadcast
+    ///
+    /// Returns Err(NotFound) if the broadcast doesn't exist (or is outside of
+    /// the reader's scope).
+    pub fn read_broadcast(
+        &self,
+        store: &BroadcastStore,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+    ) -> HandlerResult<Broadcast> {
+        if !self.can_read(&format!("{}/{}", broadcaster_id, bchannel_id)) {
+            Err(HandlerErrorKind::NotFound)?
+        }
+        Ok(store
+            .read_one(broadcaster_id, bchannel_id)?
+            .ok_or(HandlerErrorKind::NotFound)?)
+    }
+
+    /// Read the broadcasts changed since a point in their history (with a
+    /// None version when deleted)
+    ///
+    /// Also returns the change sequence number to read subsequent changes
+    /// since.
+    pub fn read_changes(
+        &self,
+        store: &BroadcastStore,
+        since: &Since,
+    ) -> HandlerResult<(HashMap<String, Option<String>>, i64)> {
+        let sequence = store.read_sequence()?;
+        let mut changes = HashMap::new();
+        // Oldest first, so the latest change to each broadcast wins
+        for entry in store.read_changes(since, sequence)? {
+            let id = format!("{}/{}", entry.broadcaster_id, entry.bchannel_id);
+            if !self.can_read(&id) {
+                continue;
+            }
+            let version = if entry.deleted {
+                None
+            } else {
+                Some(entry.version)
+            };
+            changes.insert(id, version);
+        }
+        Ok((changes, sequence))
+    }
+
+    /// Read a page of a broadcast's history, newest first
+    ///
+    /// Returns Err(NotFound) if the broadcast is outside of the reader's
+    /// scope.
+    pub fn read_history(
+        &self,
+        store: &BroadcastStore,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        limit: i64,
+        before: Option<i64>,
+    ) -> HandlerResult<Vec<HistoryEntry>> {
+        if !self.can_read(&format!("{}/{}", broadcaster_id, bchannel_id)) {
+            Err(HandlerErrorKind::NotFound)?
+        }
+        store.read_history(broadcaster_id, bchannel_id, limit, before)
+    }
 }
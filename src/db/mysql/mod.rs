@@ -0,0 +1,86 @@
+use diesel::mysql::MysqlConnection;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::result::OptionalExtension;
+use diesel::sql_types::{Integer, Nullable, Text};
+use diesel::{sql_query, Connection, QueryDsl, QueryResult, RunQueryDsl};
+use failure::ResultExt;
+use rocket::Config;
+
+use super::models::NewVersion;
+use super::pool_from_config;
+use super::schema::broadcastsv1;
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+embed_migrations!("migrations/mysql");
+
+/// Sessions are in UTC since timestamps are naive UTC (TIMESTAMP columns
+/// are converted to and from the session's time zone)
+const SESSION_SQL: &str = "SET time_zone = '+00:00'";
+
+/// A BroadcastStore backed by MySQL
+pub struct MysqlStore {
+    pool: Pool<ConnectionManager<MysqlConnection>>,
+}
+
+impl MysqlStore {
+    /// Run the diesel embedded migrations and create the connection pool
+    ///
+    /// Mysql DDL statements implicitly commit which could disrupt the pool's
+    /// begin_test_transaction during tests. So migrations run on their own
+    /// separate conn.
+    pub fn from_config(config: &Config, database_url: &str) -> Result<MysqlStore> {
+        let conn = MysqlConnection::establish(database_url)?;
+        embedded_migrations::run(&conn)?;
+        Ok(MysqlStore {
+            pool: pool_from_config(config, database_url, SESSION_SQL)?,
+        })
+    }
+
+    fn conn(&self) -> HandlerResult<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        Ok(self.pool.get().context(HandlerErrorKind::DBError)?)
+    }
+}
+
+/// Read a broadcast's current version, locking its row until the
+/// transaction commits
+fn lock_version(
+    conn: &MysqlConnection,
+    broadcaster_id: &str,
+    bchannel_id: &str,
+) -> QueryResult<Option<String>> {
+    broadcastsv1::table
+        .find((broadcaster_id, bchannel_id))
+        .select(broadcastsv1::version)
+        .for_update()
+        .first::<String>(conn)
+        .optional()
+}
+
+/// Upsert a broadcast within the current transaction, returning whether it
+/// was created
+fn upsert_broadcast(conn: &MysqlConnection, new: &NewVersion) -> HandlerResult<bool> {
+    if !new.if_match.is_empty() {
+        let current = lock_version(conn, new.broadcaster_id, new.bchannel_id)?;
+        if !new.precondition_holds(current.as_ref().map(String::as_str)) {
+            Err(HandlerErrorKind::PreconditionFailed)?
+        }
+    }
+    let affected_rows = sql_query(include_str!("upsert_broadcast.sql"))
+        .bind::<Text, _>(new.broadcaster_id)
+        .bind::<Text, _>(new.bchannel_id)
+        .bind::<Text, _>(new.version)
+        .bind::<Text, _>(new.writer)
+        .bind::<Nullable<Text>, _>(new.note)
+        .bind::<Nullable<Integer>, _>(new.ttl)
+        .bind::<Text, _>(new.broadcaster_id)
+        .bind::<Text, _>(new.bchannel_id)
+        .bind::<Text, _>(new.version)
+        .bind::<Text, _>(new.writer)
+        .bind::<Nullable<Text>, _>(new.note)
+        .bind::<Nullable<Integer>, _>(new.ttl)
+        .execute(conn)?;
+    // ON DUPLICATE KEY UPDATE reports 1 affected row for an insert
+    Ok(affected_rows == 1)
+}
+
+diesel_store!(MysqlStore, MysqlConnection);
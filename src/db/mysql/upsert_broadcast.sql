@@ -0,0 +1,3 @@
+INSERT INTO broadcastsv1 (broadcaster_id, bchannel_id, version, writer, note, ttl)
+VALUES (?, ?, ?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE version = ?, writer = ?, note = ?, ttl = ?;
@@ -0,0 +1,83 @@
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::result::OptionalExtension;
+use diesel::sql_types::{Bool, Integer, Nullable, Text};
+use diesel::{sql_query, Connection, QueryDsl, QueryResult, RunQueryDsl};
+use failure::ResultExt;
+use rocket::Config;
+
+use super::models::NewVersion;
+use super::pool_from_config;
+use super::schema::broadcastsv1;
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+embed_migrations!("migrations/postgres");
+
+/// Sessions are in UTC since timestamps are naive UTC (CURRENT_TIMESTAMP
+/// defaults are in the session's time zone)
+const SESSION_SQL: &str = "SET TIME ZONE 'UTC'";
+
+/// Result of upsert_broadcast.sql
+#[derive(QueryableByName)]
+struct Upserted {
+    /// Whether the row was inserted (xmax is only set for updated rows)
+    #[sql_type = "Bool"]
+    created: bool,
+}
+
+/// A BroadcastStore backed by PostgreSQL
+pub struct PostgresStore {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl PostgresStore {
+    /// Run the diesel embedded migrations and create the connection pool
+    pub fn from_config(config: &Config, database_url: &str) -> Result<PostgresStore> {
+        let conn = PgConnection::establish(database_url)?;
+        embedded_migrations::run(&conn)?;
+        Ok(PostgresStore {
+            pool: pool_from_config(config, database_url, SESSION_SQL)?,
+        })
+    }
+
+    fn conn(&self) -> HandlerResult<PooledConnection<ConnectionManager<PgConnection>>> {
+        Ok(self.pool.get().context(HandlerErrorKind::DBError)?)
+    }
+}
+
+/// Read a broadcast's current version, locking its row until the
+/// transaction commits
+fn lock_version(
+    conn: &PgConnection,
+    broadcaster_id: &str,
+    bchannel_id: &str,
+) -> QueryResult<Option<String>> {
+    broadcastsv1::table
+        .find((broadcaster_id, bchannel_id))
+        .select(broadcastsv1::version)
+        .for_update()
+        .first::<String>(conn)
+        .optional()
+}
+
+/// Upsert a broadcast within the current transaction, returning whether it
+/// was created
+fn upsert_broadcast(conn: &PgConnection, new: &NewVersion) -> HandlerResult<bool> {
+    if !new.if_match.is_empty() {
+        let current = lock_version(conn, new.broadcaster_id, new.bchannel_id)?;
+        if !new.precondition_holds(current.as_ref().map(String::as_str)) {
+            Err(HandlerErrorKind::PreconditionFailed)?
+        }
+    }
+    let upserted = sql_query(include_str!("upsert_broadcast.sql"))
+        .bind::<Text, _>(new.broadcaster_id)
+        .bind::<Text, _>(new.bchannel_id)
+        .bind::<Text, _>(new.version)
+        .bind::<Text, _>(new.writer)
+        .bind::<Nullable<Text>, _>(new.note)
+        .bind::<Nullable<Integer>, _>(new.ttl)
+        .get_result::<Upserted>(conn)?;
+    Ok(upserted.created)
+}
+
+diesel_store!(PostgresStore, PgConnection);
@@ -0,0 +1,10 @@
+INSERT INTO broadcastsv1 (broadcaster_id, bchannel_id, version, writer, note, ttl)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (broadcaster_id, bchannel_id)
+DO UPDATE SET
+    version = EXCLUDED.version,
+    writer = EXCLUDED.writer,
+    note = EXCLUDED.note,
+    ttl = EXCLUDED.ttl,
+    last_updated = CURRENT_TIMESTAMP
+RETURNING (xmax = 0) AS created;
@@ -0,0 +1,229 @@
+/// Implement BroadcastStore for a diesel backed store
+///
+/// The backends only differ in how they upsert a broadcast, so the invoking
+/// module provides:
+///
+/// - `$store::conn()`, returning (a guard of) a `$conn`
+/// - `lock_version(conn, broadcaster_id, bchannel_id)`, reading a
+///   broadcast's current version, locked until the transaction commits
+/// - `upsert_broadcast(conn, new)`, checking the If-Match precondition and
+///   upserting the broadcast, returning whether it was created
+///
+/// The queries shared by every backend are generated here.
+macro_rules! diesel_store {
+    ($store:ident, $conn:ident) => {
+        mod store {
+            use diesel::dsl::{max, sql};
+            use diesel::result::{Error as DieselError, OptionalExtension};
+            use diesel::sql_types::Integer;
+            use diesel::{self, Connection, ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl};
+            use failure::ResultExt;
+
+            use super::{lock_version, upsert_broadcast, $conn, $store};
+            use db::models::{Broadcast, HistoryEntry, NewVersion, Since};
+            use db::schema::{broadcastsv1, broadcastsv1_history, broadcastsv1_sequence};
+            use db::BroadcastStore;
+            use error::{HandlerError, HandlerErrorKind, HandlerResult, Result};
+
+            /// Reserve count change sequence numbers (history ids), returning the
+            /// first
+            ///
+            /// Ids allocated at insert (AUTO_INCREMENT, BIGSERIAL) aren't safe as
+            /// a cursor: a transaction may commit a lower id after a higher one
+            /// is visible. The counter row instead stays locked until the
+            /// transaction commits, so writers commit their ids in order. It's
+            /// locked before any broadcast to avoid deadlocks.
+            fn reserve_sequence(conn: &$conn, count: i64) -> QueryResult<i64> {
+                diesel::update(broadcastsv1_sequence::table)
+                    .set(
+                        broadcastsv1_sequence::sequence
+                            .eq(broadcastsv1_sequence::sequence + count),
+                    )
+                    .execute(conn)?;
+                let sequence = broadcastsv1_sequence::table
+                    .select(broadcastsv1_sequence::sequence)
+                    .first::<i64>(conn)?;
+                Ok(sequence - count + 1)
+            }
+
+            /// Upsert a broadcast within the current transaction, recording it
+            /// in the history as id
+            fn upsert_version(conn: &$conn, new: &NewVersion, id: i64) -> HandlerResult<bool> {
+                let created = upsert_broadcast(conn, new)?;
+                diesel::insert_into(broadcastsv1_history::table)
+                    .values((
+                        broadcastsv1_history::id.eq(id),
+                        broadcastsv1_history::broadcaster_id.eq(new.broadcaster_id),
+                        broadcastsv1_history::bchannel_id.eq(new.bchannel_id),
+                        broadcastsv1_history::version.eq(new.version),
+                        broadcastsv1_history::writer.eq(new.writer),
+                    ))
+                    .execute(conn)?;
+                Ok(created)
+            }
+
+            impl BroadcastStore for $store {
+                fn upsert(&self, new: &NewVersion) -> HandlerResult<bool> {
+                    let conn = self.conn()?;
+                    conn.transaction::<_, HandlerError, _>(|| {
+                        let id = reserve_sequence(&conn, 1)?;
+                        upsert_version(&conn, new, id)
+                    })
+                }
+
+                fn upsert_many(&self, new: &[NewVersion]) -> HandlerResult<Vec<bool>> {
+                    let conn = self.conn()?;
+                    conn.transaction::<_, HandlerError, _>(|| {
+                        let first = reserve_sequence(&conn, new.len() as i64)?;
+                        new.iter()
+                            .zip(first..)
+                            .map(|(new, id)| upsert_version(&conn, new, id))
+                            .collect()
+                    })
+                }
+
+                fn read_all(&self) -> HandlerResult<Vec<Broadcast>> {
+                    Ok(broadcastsv1::table
+                        .select((
+                            broadcastsv1::broadcaster_id,
+                            broadcastsv1::bchannel_id,
+                            broadcastsv1::version,
+                            broadcastsv1::created,
+                            broadcastsv1::last_updated,
+                            broadcastsv1::writer,
+                            broadcastsv1::note,
+                            broadcastsv1::ttl,
+                        ))
+                        .load::<Broadcast>(&*self.conn()?)
+                        .context(HandlerErrorKind::DBError)?)
+                }
+
+                fn read_broadcaster(&self, broadcaster_id: &str) -> HandlerResult<Vec<Broadcast>> {
+                    Ok(broadcastsv1::table
+                        .filter(broadcastsv1::broadcaster_id.eq(broadcaster_id))
+                        .select((
+                            broadcastsv1::broadcaster_id,
+                            broadcastsv1::bchannel_id,
+                            broadcastsv1::version,
+                            broadcastsv1::created,
+                            broadcastsv1::last_updated,
+                            broadcastsv1::writer,
+                            broadcastsv1::note,
+                            broadcastsv1::ttl,
+                        ))
+                        .load::<Broadcast>(&*self.conn()?)
+                        .context(HandlerErrorKind::DBError)?)
+                }
+
+                fn read_one(
+                    &self,
+                    broadcaster_id: &str,
+                    bchannel_id: &str,
+                ) -> HandlerResult<Option<Broadcast>> {
+                    Ok(broadcastsv1::table
+                        .find((broadcaster_id, bchannel_id))
+                        .select((
+                            broadcastsv1::broadcaster_id,
+                            broadcastsv1::bchannel_id,
+                            broadcastsv1::version,
+                            broadcastsv1::created,
+                            broadcastsv1::last_updated,
+                            broadcastsv1::writer,
+                            broadcastsv1::note,
+                            broadcastsv1::ttl,
+                        ))
+                        .first::<Broadcast>(&*self.conn()?)
+                        .optional()
+                        .context(HandlerErrorKind::DBError)?)
+                }
+
+                fn read_history(
+                    &self,
+                    broadcaster_id: &str,
+                    bchannel_id: &str,
+                    limit: i64,
+                    before: Option<i64>,
+                ) -> HandlerResult<Vec<HistoryEntry>> {
+                    Ok(broadcastsv1_history::table
+                        .filter(broadcastsv1_history::broadcaster_id.eq(broadcaster_id))
+                        .filter(broadcastsv1_history::bchannel_id.eq(bchannel_id))
+                        .filter(broadcastsv1_history::id.lt(before.unwrap_or(i64::max_value())))
+                        .order(broadcastsv1_history::id.desc())
+                        .limit(limit)
+                        .load::<HistoryEntry>(&*self.conn()?)
+                        .context(HandlerErrorKind::DBError)?)
+                }
+
+                fn read_sequence(&self) -> HandlerResult<i64> {
+                    Ok(broadcastsv1_history::table
+                        .select(max(broadcastsv1_history::id))
+                        .first::<Option<i64>>(&*self.conn()?)
+                        .context(HandlerErrorKind::DBError)?
+                        .unwrap_or(0))
+                }
+
+                fn read_changes(
+                    &self,
+                    since: &Since,
+                    until: i64,
+                ) -> HandlerResult<Vec<HistoryEntry>> {
+                    let conn = self.conn()?;
+                    let changes = broadcastsv1_history::table
+                        .filter(broadcastsv1_history::id.le(until))
+                        .order(broadcastsv1_history::id);
+                    let changes = match *since {
+                        Since::Sequence(sequence) => changes
+                            .filter(broadcastsv1_history::id.gt(sequence))
+                            .load::<HistoryEntry>(&*conn),
+                        Since::Timestamp(timestamp) => changes
+                            .filter(broadcastsv1_history::created.gt(timestamp))
+                            .load::<HistoryEntry>(&*conn),
+                    };
+                    Ok(changes.context(HandlerErrorKind::DBError)?)
+                }
+
+                fn delete(
+                    &self,
+                    writer: &str,
+                    broadcaster_id: &str,
+                    bchannel_id: &str,
+                ) -> HandlerResult<bool> {
+                    let conn = self.conn()?;
+                    Ok(conn
+                        .transaction::<_, DieselError, _>(|| {
+                            let id = reserve_sequence(&conn, 1)?;
+                            let version = match lock_version(&conn, broadcaster_id, bchannel_id)? {
+                                Some(version) => version,
+                                None => return Ok(false),
+                            };
+                            diesel::delete(broadcastsv1::table.find((broadcaster_id, bchannel_id)))
+                                .execute(&*conn)?;
+                            diesel::insert_into(broadcastsv1_history::table)
+                                .values((
+                                    broadcastsv1_history::id.eq(id),
+                                    broadcastsv1_history::broadcaster_id.eq(broadcaster_id),
+                                    broadcastsv1_history::bchannel_id.eq(bchannel_id),
+                                    broadcastsv1_history::version.eq(version),
+                                    broadcastsv1_history::writer.eq(writer),
+                                    broadcastsv1_history::deleted.eq(true),
+                                ))
+                                .execute(&*conn)?;
+                            Ok(true)
+                        })
+                        .context(HandlerErrorKind::DBError)?)
+                }
+
+                fn health_check(&self) -> Result<()> {
+                    let conn = self.conn()?;
+                    match broadcastsv1::table
+                        .select(sql::<Integer>("1"))
+                        .get_result::<i32>(&*conn)
+                    {
+                        Ok(_) | Err(DieselError::NotFound) => Ok(()),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+            }
+        }
+    };
+}
@@ -2,7 +2,30 @@ table! {
     broadcastsv1 (broadcaster_id, bchannel_id) {
         broadcaster_id -> Varchar,
         bchannel_id -> Varchar,
+        created -> Timestamp,
         last_updated -> Timestamp,
         version -> Varchar,
+        writer -> Varchar,
+        note -> Nullable<Varchar>,
+        ttl -> Nullable<Integer>,
+    }
+}
+
+table! {
+    broadcastsv1_history (id) {
+        id -> BigInt,
+        broadcaster_id -> Varchar,
+        bchannel_id -> Varchar,
+        version -> Varchar,
+        writer -> Varchar,
+        created -> Timestamp,
+        deleted -> Bool,
+    }
+}
+
+table! {
+    broadcastsv1_sequence (id) {
+        id -> Integer,
+        sequence -> BigInt,
     }
 }
@@ -0,0 +1,85 @@
+use std::sync::{Mutex, MutexGuard};
+
+use diesel::result::OptionalExtension;
+use diesel::sql_types::{Integer, Nullable, Text};
+use diesel::sqlite::SqliteConnection;
+use diesel::{sql_query, Connection, QueryDsl, QueryResult, RunQueryDsl};
+use rocket::Config;
+
+use super::models::NewVersion;
+use super::schema::broadcastsv1;
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+embed_migrations!("migrations/sqlite");
+
+/// A BroadcastStore backed by SQLite
+///
+/// SQLite serializes writes anyway, so a single connection is shared rather
+/// than pooled (this also allows ":memory:" databases). The
+/// database_pool_max_size setting is ignored.
+pub struct SqliteStore {
+    conn: Mutex<SqliteConnection>,
+}
+
+impl SqliteStore {
+    /// Open the database and run the diesel embedded migrations
+    ///
+    /// database_url is of the form "sqlite://<path>" (e.g.
+    /// "sqlite:///var/lib/megaphone.db" or "sqlite://:memory:").
+    pub fn from_config(config: &Config, database_url: &str) -> Result<SqliteStore> {
+        let path = &database_url["sqlite://".len()..];
+        let conn = SqliteConnection::establish(path)?;
+        embedded_migrations::run(&conn)?;
+        if config
+            .get_bool("database_use_test_transactions")
+            .unwrap_or(false)
+        {
+            conn.begin_test_transaction()?;
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> HandlerResult<MutexGuard<SqliteConnection>> {
+        Ok(self.conn.lock().map_err(|_| HandlerErrorKind::DBError)?)
+    }
+}
+
+/// Read a broadcast's current version
+///
+/// SQLite has no row locks (nor FOR UPDATE), the shared connection already
+/// serializes writers.
+fn lock_version(
+    conn: &SqliteConnection,
+    broadcaster_id: &str,
+    bchannel_id: &str,
+) -> QueryResult<Option<String>> {
+    broadcastsv1::table
+        .find((broadcaster_id, bchannel_id))
+        .select(broadcastsv1::version)
+        .first::<String>(conn)
+        .optional()
+}
+
+/// Upsert a broadcast within the current transaction, returning whether it
+/// was created
+fn upsert_broadcast(conn: &SqliteConnection, new: &NewVersion) -> HandlerResult<bool> {
+    // ON CONFLICT reports 1 affected row for both an insert and an
+    // update, so read any existing row first
+    let current = lock_version(conn, new.broadcaster_id, new.bchannel_id)?;
+    if !new.precondition_holds(current.as_ref().map(String::as_str)) {
+        Err(HandlerErrorKind::PreconditionFailed)?
+    }
+    sql_query(include_str!("upsert_broadcast.sql"))
+        .bind::<Text, _>(new.broadcaster_id)
+        .bind::<Text, _>(new.bchannel_id)
+        .bind::<Text, _>(new.version)
+        .bind::<Text, _>(new.writer)
+        .bind::<Nullable<Text>, _>(new.note)
+        .bind::<Nullable<Integer>, _>(new.ttl)
+        .execute(conn)?;
+    Ok(current.is_none())
+}
+
+diesel_store!(SqliteStore, SqliteConnection);
@@ -0,0 +1,9 @@
+INSERT INTO broadcastsv1 (broadcaster_id, bchannel_id, version, writer, note, ttl)
+VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT (broadcaster_id, bchannel_id)
+DO UPDATE SET
+    version = excluded.version,
+    writer = excluded.writer,
+    note = excluded.note,
+    ttl = excluded.ttl,
+    last_updated = CURRENT_TIMESTAMP;
@@ -1,3 +0,0 @@
-INSERT INTO broadcastsv1 (broadcaster_id, bchannel_id, version)
-VALUES (?, ?, ?)
-ON DUPLICATE KEY UPDATE version = ?;
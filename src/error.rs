@@ -9,6 +9,7 @@
 use std::fmt;
 use std::result;
 
+use diesel::result::Error as DieselError;
 use failure::{Backtrace, Context, Error, Fail};
 use rocket::http::{Header, Status};
 use rocket::response::{Responder, Response};
@@ -35,6 +36,12 @@ pub enum HandlerErrorKind {
     MissingAuth,
     #[fail(display = "Invalid authorization header")]
     InvalidAuth,
+    #[fail(display = "Unsupported authorization scheme (must be Bearer)")]
+    UnsupportedAuthScheme,
+
+    /// 400 Bad Request
+    #[fail(display = "Malformed Bearer token")]
+    MalformedAuth,
 
     /// 403 Forbidden (unauthorized)
     #[fail(display = "Access denied to the requested resource")]
@@ -43,14 +50,39 @@ pub enum HandlerErrorKind {
     /// 404 Not Found
     #[fail(display = "Not Found")]
     NotFound,
+    #[fail(display = "Server-Sent Events are served on port {}", _0)]
+    SseElsewhere(u16),
+    #[fail(display = "Server-Sent Events are disabled")]
+    SseDisabled,
+
+    /// 412 Precondition Failed
+    #[fail(display = "Current version does not match If-Match")]
+    PreconditionFailed,
+
+    /// 413 Payload Too Large
+    #[fail(display = "Request body too large")]
+    PayloadTooLarge,
 
     #[fail(display = "A database error occurred")]
     DBError,
+    #[fail(display = "Too many concurrent connections, retry later")]
+    TooManyConnections,
 
     #[fail(display = "Version information not included in body of update")]
     MissingVersionDataError,
     #[fail(display = "Invalid Version info (must be URL safe Base 64)")]
     InvalidVersionDataError,
+    #[fail(display = "Invalid Versions (must be a JSON object of bchannel_id to version)")]
+    InvalidVersionsDataError,
+    #[fail(
+        display = "Invalid broadcaster_id or bchannel_id (must be 1 to 64 or 128 of [A-Za-z0-9_.-])"
+    )]
+    InvalidIdError,
+    #[fail(display = "Invalid note (must be at most 1000 characters) or ttl (must be positive)")]
+    InvalidMetadataError,
+
+    #[fail(display = "Invalid query string")]
+    InvalidQueryError,
 
     #[fail(display = "Unexpected rocket error: {:?}", _0)]
     RocketError(rocket::Error), // rocket::Error isn't a std Error (so no #[cause])
@@ -62,10 +94,18 @@ impl HandlerErrorKind {
     /// Return a rocket response Status to be rendered for an error
     pub fn http_status(&self) -> Status {
         match *self {
-            HandlerErrorKind::MissingAuth | HandlerErrorKind::InvalidAuth => Status::Unauthorized,
+            HandlerErrorKind::MissingAuth
+            | HandlerErrorKind::InvalidAuth
+            | HandlerErrorKind::UnsupportedAuthScheme => Status::Unauthorized,
             HandlerErrorKind::Unauthorized => Status::Forbidden,
-            HandlerErrorKind::NotFound => Status::NotFound,
-            HandlerErrorKind::DBError => Status::ServiceUnavailable,
+            HandlerErrorKind::NotFound
+            | HandlerErrorKind::SseElsewhere(_)
+            | HandlerErrorKind::SseDisabled => Status::NotFound,
+            HandlerErrorKind::PreconditionFailed => Status::PreconditionFailed,
+            HandlerErrorKind::PayloadTooLarge => Status::PayloadTooLarge,
+            HandlerErrorKind::DBError | HandlerErrorKind::TooManyConnections => {
+                Status::ServiceUnavailable
+            }
             _ => Status::BadRequest,
         }
     }
@@ -99,6 +139,12 @@ impl From<HandlerErrorKind> for HandlerError {
     }
 }
 
+impl From<DieselError> for HandlerError {
+    fn from(err: DieselError) -> HandlerError {
+        err.context(HandlerErrorKind::DBError).into()
+    }
+}
+
 impl From<Context<HandlerErrorKind>> for HandlerError {
     fn from(inner: Context<HandlerErrorKind>) -> HandlerError {
         HandlerError { inner: inner }
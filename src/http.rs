@@ -1,26 +1,31 @@
+use std::collections::HashMap;
 use std::convert::Into;
-use std::error::Error;
 use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use diesel::dsl::sql;
-use diesel::result::Error as DieselError;
-use diesel::sql_types::Integer;
-use diesel::{QueryDsl, RunQueryDsl};
-use failure::ResultExt;
+use failure::{err_msg, ResultExt};
 use rocket::Outcome::{Failure, Success};
+use rocket::config::{Config, ConfigError};
 use rocket::data::{self, FromData};
-use rocket::http::Status;
+use rocket::http::{Header, RawStr, Status};
 use rocket::outcome::IntoOutcome;
-use rocket::request::{self, FromRequest};
-use rocket::response::{content, status};
-use rocket::{self, Data, Request, Rocket};
+use rocket::request::{self, FormItems, FromForm, FromFormValue, FromRequest};
+use rocket::response::{self, content, status, Responder, Response};
+use rocket::{self, Data, Request, Rocket, State};
 use rocket_contrib::Json;
+use serde_json;
 
 use auth;
 use db;
-use db::models::{Broadcaster, Reader};
-use db::schema::broadcastsv1;
+use db::models::{BroadcastInfo, Broadcaster, Reader, Since, VersionData};
+use db::BroadcastStore;
 use error::{HandlerError, HandlerErrorKind, HandlerResult, Result, VALIDATION_FAILED};
+use notify::{self, Limit, Notifier, NotifyingStore};
+use sse;
+use validate::{validate_metadata, Validator};
+use webhook;
+use websocket;
 
 impl<'a, 'r> FromRequest<'a, 'r> for Broadcaster {
     type Error = HandlerError;
@@ -30,30 +35,96 @@ impl<'a, 'r> FromRequest<'a, 'r> for Broadcaster {
     }
 }
 
-/// A new broadcast
+/// A new broadcast: a bare version or (with a JSON Content-Type) a version
+/// with metadata
 struct VersionInput {
-    value: String,
+    value: VersionData,
 }
 
 impl FromData for VersionInput {
     type Error = HandlerError;
 
-    fn from_data(_: &Request, data: Data) -> data::Outcome<Self, HandlerError> {
-        let mut string = String::new();
-        data.open()
-            .read_to_string(&mut string)
-            .context(HandlerErrorKind::MissingVersionDataError)
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, HandlerError> {
+        let body = read_body(request, data).into_outcome(VALIDATION_FAILED)?;
+        let value = if request.content_type().map_or(false, |ct| ct.is_json()) {
+            serde_json::from_str::<VersionData>(&body)
+                .context(HandlerErrorKind::InvalidVersionDataError)
+                .map_err(Into::into)
+                .into_outcome(VALIDATION_FAILED)?
+        } else {
+            VersionData::new(body)
+        };
+        let bchannel_id = request
+            .get_param::<String>(1)
+            .map_err(|e| HandlerErrorKind::RocketError(e).into())
+            .into_outcome(VALIDATION_FAILED)?;
+        validate_versions(request, Some((&bchannel_id, &value.version)))
+            .and_then(|_| validate_metadata(value.note.as_ref().map(String::as_str), value.ttl))
+            .into_outcome(VALIDATION_FAILED)?;
+        Success(VersionInput { value: value })
+    }
+}
+
+/// New versions of multiple broadcasts: a JSON object of bchannel_id to
+/// version
+struct VersionsInput {
+    values: HashMap<String, String>,
+}
+
+impl FromData for VersionsInput {
+    type Error = HandlerError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, HandlerError> {
+        let body = read_body(request, data).into_outcome(VALIDATION_FAILED)?;
+        let values: HashMap<String, String> = serde_json::from_str(&body)
+            .context(HandlerErrorKind::InvalidVersionsDataError)
             .map_err(Into::into)
             .into_outcome(VALIDATION_FAILED)?;
-        if string.is_empty() {
+        if values.is_empty() {
             return Failure((
                 VALIDATION_FAILED,
-                HandlerErrorKind::InvalidVersionDataError.into(),
+                HandlerErrorKind::InvalidVersionsDataError.into(),
             ));
         }
-        // TODO Validate the version info
-        Success(VersionInput { value: string })
+        validate_versions(request, &values).into_outcome(VALIDATION_FAILED)?;
+        Success(VersionsInput { values: values })
+    }
+}
+
+/// Read a request body of at most ROCKET_MAX_BODY_SIZE bytes
+fn read_body(request: &Request, data: Data) -> HandlerResult<String> {
+    let max_body_size = request
+        .guard::<State<Validator>>()
+        .success_or(HandlerErrorKind::InternalError)?
+        .max_body_size;
+    let mut body = Vec::new();
+    // Read one byte past the limit to detect larger bodies
+    data.open()
+        .take(max_body_size + 1)
+        .read_to_end(&mut body)
+        .context(HandlerErrorKind::MissingVersionDataError)?;
+    if body.len() as u64 > max_body_size {
+        Err(HandlerErrorKind::PayloadTooLarge)?
     }
+    Ok(String::from_utf8(body).context(HandlerErrorKind::MissingVersionDataError)?)
+}
+
+/// Validate new versions (keyed by bchannel_id) of the request's broadcaster
+fn validate_versions<'a, I>(request: &Request, versions: I) -> HandlerResult<()>
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    // param should be guaranteed on the path when we're called
+    let broadcaster_id = request
+        .get_param::<String>(0)
+        .map_err(HandlerErrorKind::RocketError)?;
+    let validator = request
+        .guard::<State<Validator>>()
+        .success_or(HandlerErrorKind::InternalError)?;
+    for (bchannel_id, version) in versions {
+        validator.validate(&broadcaster_id, bchannel_id, version)?;
+    }
+    Ok(())
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for Reader {
@@ -64,18 +135,228 @@ impl<'a, 'r> FromRequest<'a, 'r> for Reader {
     }
 }
 
+/// Request guard for an optional query string
+///
+/// Unlike a route's `?<query>` this also matches requests lacking a query
+/// string (yielding T's defaults).
+struct Query<T>(T);
+
+impl<'a, 'r, T: FromForm<'a>> FromRequest<'a, 'r> for Query<T> {
+    type Error = HandlerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, HandlerError> {
+        let mut items = FormItems::from(request.uri().query().unwrap_or(""));
+        T::from_form(&mut items, false)
+            .map(Query)
+            .map_err(|_| HandlerErrorKind::InvalidQueryError.into())
+            .into_outcome(VALIDATION_FAILED)
+    }
+}
+
+/// Request guard for the ETags listed in an If-None-Match header
+struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+    /// Determine if an (unquoted) ETag matches
+    fn matches(&self, etag: &str) -> bool {
+        self.0.iter().any(|tag| tag == "*" || tag == etag)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Success(IfNoneMatch(parse_etags(request, "If-None-Match", false)))
+    }
+}
+
+/// Request guard for the ETags listed in an If-Match header
+struct IfMatch(Vec<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Success(IfMatch(parse_etags(request, "If-Match", true)))
+    }
+}
+
+/// Parse the list of ETags in an If-Match/If-None-Match header, unquoted
+///
+/// Weak validators' prefixes are ignored unless strong comparison is
+/// required (If-Match), in which case they're kept as is so they never match.
+fn parse_etags(request: &Request, name: &str, strong: bool) -> Vec<String> {
+    request
+        .headers()
+        .get(name)
+        .flat_map(|value| value.split(','))
+        .map(|tag| {
+            let tag = tag.trim();
+            if strong && tag.starts_with("W/") {
+                tag.to_string()
+            } else {
+                tag.trim_left_matches("W/").trim_matches('"').to_string()
+            }
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// The version table dump's ETag: the change sequence number it's current
+/// as of (distinguished by format)
+///
+/// Prefixed so it's never mistaken for a since parameter's sequence number.
+fn dump_etag(sequence: i64, format: Format) -> String {
+    match format {
+        Format::Versions => format!("s{}", sequence),
+        Format::Full => format!("s{}-full", sequence),
+    }
+}
+
+/// A JSON response with an (optional) ETag
+///
+/// Without JSON (because the request's If-None-Match matched the ETag) it's
+/// rendered as a bodiless 304 Not Modified.
+struct TaggedJson {
+    etag: Option<String>,
+    json: Option<Json>,
+}
+
+impl<'r> Responder<'r> for TaggedJson {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut builder = match self.json {
+            Some(json) => Response::build_from(json.respond_to(request)?),
+            None => {
+                let mut builder = Response::build();
+                builder.status(Status::NotModified);
+                builder
+            }
+        };
+        if let Some(etag) = self.etag {
+            builder.header(Header::new("ETag", format!(r#""{}""#, etag)));
+        }
+        builder.ok()
+    }
+}
+
+/// An optional query parameter
+///
+/// Unlike an Option (which is None when its value is invalid) an invalid
+/// value fails the query with an InvalidQueryError.
+struct Param<T>(Option<T>);
+
+impl<'v, T: FromFormValue<'v>> FromFormValue<'v> for Param<T> {
+    type Error = T::Error;
+
+    fn from_form_value(value: &'v RawStr) -> ::std::result::Result<Self, T::Error> {
+        T::from_form_value(value).map(|value| Param(Some(value)))
+    }
+
+    fn default() -> Option<Self> {
+        Some(Param(None))
+    }
+}
+
+/// Pagination of a broadcast's history
+#[derive(FromForm)]
+struct HistoryQuery {
+    limit: Param<i64>,
+    /// Only return entries older than this history id
+    before: Param<i64>,
+}
+
+/// Version to restore in a rollback
+#[derive(FromForm)]
+struct RollbackQuery {
+    /// History id of the version (defaults to the previous version)
+    to: Param<i64>,
+}
+
+/// Format of the broadcasts in reader responses
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// Only their versions (the default)
+    Versions,
+    /// Their versions and metadata
+    Full,
+}
+
+impl<'v> FromFormValue<'v> for Format {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> ::std::result::Result<Self, &'v RawStr> {
+        match value.as_str() {
+            "versions" => Ok(Format::Versions),
+            "full" => Ok(Format::Full),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct FormatQuery {
+    format: Param<Format>,
+}
+
+/// Long-polling/delta reads of the version table
+#[derive(FromForm)]
+struct WaitQuery {
+    /// Seconds to wait for a change
+    wait: Param<u64>,
+    /// An ETag, change sequence number or RFC 3339 timestamp
+    since: Option<String>,
+    format: Param<Format>,
+}
+
+/// Maximum seconds a long-poll waits
+const WAIT_MAX: u64 = 60;
+
+/// Concurrently waiting long-polls, each occupying one of rocket's workers
+///
+/// Limited to ROCKET_MAX_WAITERS (by default half of the workers) so others
+/// remain for the remaining requests.
+struct Waiters(Limit);
+
+impl Waiters {
+    fn from_config(config: &Config) -> Result<Waiters> {
+        let max_waiters = match config.get_int("max_waiters") {
+            Ok(max_waiters) if max_waiters >= 0 => max_waiters as usize,
+            Err(ConfigError::Missing(_)) => (config.workers / 2) as usize,
+            _ => Err(err_msg("Invalid ROCKET_MAX_WAITERS"))?,
+        };
+        Ok(Waiters(Limit::new(max_waiters)))
+    }
+}
+
+/// The port Server-Sent Events are served on (ROCKET_SSE_PORT), if any
+struct SsePort(Option<u16>);
+
+/// Default and maximum number of history entries returned at once
+const HISTORY_DEFAULT_LIMIT: i64 = 20;
+const HISTORY_MAX_LIMIT: i64 = 100;
+
 // REST Functions
 
 /// Set a version for a broadcaster / bchannel
+///
+/// Responds with 412 Precondition Failed when the If-Match header doesn't
+/// match the current version.
 #[put("/v1/broadcasts/<_broadcaster_id>/<bchannel_id>", data = "<version>")]
 fn broadcast(
-    conn: db::Conn,
+    store: db::Store,
     broadcaster: HandlerResult<Broadcaster>,
     _broadcaster_id: String,
     bchannel_id: String,
+    if_match: IfMatch,
     version: HandlerResult<VersionInput>,
 ) -> HandlerResult<status::Custom<Json>> {
-    let created = broadcaster?.broadcast_new_version(&conn, bchannel_id, version?.value)?;
+    let created = broadcaster?.broadcast_new_version(
+        &*store,
+        bchannel_id,
+        version?.value,
+        &if_match.0,
+    )?;
     let status = if created { Status::Created } else { Status::Ok };
     Ok(status::Custom(
         status,
@@ -85,30 +366,292 @@ fn broadcast(
     ))
 }
 
+/// Broadcast new versions of multiple bchannels in a single transaction
+///
+/// Responds with each broadcast's status: 201 if it was created, otherwise
+/// 200.
+#[post("/v1/broadcasts/<_broadcaster_id>", data = "<versions>")]
+fn broadcast_many(
+    store: db::Store,
+    broadcaster: HandlerResult<Broadcaster>,
+    _broadcaster_id: String,
+    versions: HandlerResult<VersionsInput>,
+) -> HandlerResult<Json> {
+    let created = broadcaster?.broadcast_new_versions(&*store, &versions?.values)?;
+    let broadcasts: HashMap<_, _> = created
+        .into_iter()
+        .map(|(bchannel_id, created)| {
+            let status = if created { Status::Created } else { Status::Ok };
+            (bchannel_id, status.code)
+        })
+        .collect();
+    Ok(Json(json!({
+        "code": 200,
+        "broadcasts": broadcasts
+    })))
+}
+
+/// Delete a broadcaster / bchannel
+#[delete("/v1/broadcasts/<_broadcaster_id>/<bchannel_id>")]
+fn delete_broadcast(
+    store: db::Store,
+    broadcaster: HandlerResult<Broadcaster>,
+    _broadcaster_id: String,
+    bchannel_id: String,
+) -> HandlerResult<Json> {
+    broadcaster?.delete_broadcast(&*store, bchannel_id)?;
+    Ok(Json(json!({
+        "code": 200
+    })))
+}
+
+/// Roll a broadcaster / bchannel back to a previous version
+#[post("/v1/broadcasts/<_broadcaster_id>/<bchannel_id>/rollback")]
+fn rollback(
+    store: db::Store,
+    broadcaster: HandlerResult<Broadcaster>,
+    _broadcaster_id: String,
+    bchannel_id: String,
+    query: HandlerResult<Query<RollbackQuery>>,
+) -> HandlerResult<Json> {
+    let broadcaster = broadcaster?;
+    let version = broadcaster.rollback(&*store, bchannel_id, query?.0.to.0)?;
+    Ok(Json(json!({
+        "code": 200,
+        "version": version
+    })))
+}
+
 /// Dump the current version table
+///
+/// Responds with 304 Not Modified when the If-None-Match header (or the since
+/// parameter) matches the dump's ETag.
+///
+/// When since is a change sequence number or an RFC 3339 timestamp only the
+/// broadcasts changed since then are included (with a null version when
+/// deleted), along with a cursor: the sequence number to read subsequent
+/// changes since.
+///
+/// With the wait parameter this first long-polls up to wait seconds for a
+/// change, responding with a 503 when ROCKET_MAX_WAITERS requests already
+/// are.
+///
+/// With format=full each broadcast's version is replaced by an object of its
+/// version and metadata.
 #[get("/v1/broadcasts")]
-fn get_broadcasts(conn: db::Conn, reader: HandlerResult<Reader>) -> HandlerResult<Json> {
-    let broadcasts = reader?.read_broadcasts(&conn)?;
+fn get_broadcasts(
+    store: db::Store,
+    notifier: State<Arc<Notifier>>,
+    waiters: State<Waiters>,
+    reader: HandlerResult<Reader>,
+    if_none_match: IfNoneMatch,
+    query: HandlerResult<Query<WaitQuery>>,
+) -> HandlerResult<TaggedJson> {
+    let reader = reader?;
+    let query = query?.0;
+    let format = query.format.0.unwrap_or(Format::Versions);
+    let mut known = if_none_match;
+    let since = match query.since {
+        Some(since) => match Since::parse(&since) {
+            Some(since) => Some(since),
+            None => {
+                known.0.push(since.trim_matches('"').to_string());
+                None
+            }
+        },
+        None => None,
+    };
+    let wait = query.wait.0.unwrap_or(0).min(WAIT_MAX);
+    let deadline = Instant::now() + Duration::from_secs(wait);
+    let mut waiter = None;
+    loop {
+        // Read the sequence first so no change is missed
+        let sequence = notifier.sequence();
+        let (response, changed) = match since {
+            Some(ref since) => read_delta(&reader, &*store, since)?,
+            None => read_dump(&reader, &*store, &known, format)?,
+        };
+        let now = Instant::now();
+        if changed || now >= deadline {
+            return Ok(response);
+        }
+        if waiter.is_none() {
+            waiter = Some(waiters.0.reserve()?);
+        }
+        if !notifier.wait(sequence, deadline - now) {
+            return Ok(response);
+        }
+    }
+}
+
+/// Read the version table, unless it matches a known ETag
+///
+/// Also returns whether it didn't match.
+fn read_dump(
+    reader: &Reader,
+    store: &BroadcastStore,
+    known: &IfNoneMatch,
+    format: Format,
+) -> HandlerResult<(TaggedJson, bool)> {
+    // Read the sequence first: a change committed between the two reads at
+    // worst causes a later request's (unnecessary) full response
+    let etag = dump_etag(store.read_sequence()?, format);
+    if known.matches(&etag) {
+        return Ok((
+            TaggedJson {
+                etag: Some(etag),
+                json: None,
+            },
+            false,
+        ));
+    }
+    let broadcasts = match format {
+        Format::Versions => json!(reader.read_broadcasts(store)?),
+        Format::Full => json!(reader.read_broadcasts_full(store)?),
+    };
+    Ok((
+        TaggedJson {
+            etag: Some(etag),
+            json: Some(Json(json!({
+                "code": 200,
+                "broadcasts": broadcasts
+            }))),
+        },
+        true,
+    ))
+}
+
+/// Read the broadcasts changed since a point in their history
+///
+/// Also returns whether there were any.
+fn read_delta(
+    reader: &Reader,
+    store: &BroadcastStore,
+    since: &Since,
+) -> HandlerResult<(TaggedJson, bool)> {
+    let (broadcasts, cursor) = reader.read_changes(store, since)?;
+    let changed = !broadcasts.is_empty();
+    Ok((
+        TaggedJson {
+            etag: None,
+            json: Some(Json(json!({
+                "code": 200,
+                "broadcasts": broadcasts,
+                "cursor": cursor
+            }))),
+        },
+        changed,
+    ))
+}
+
+/// Point readers at the Server-Sent Events stream, which is served on its
+/// own port rather than by rocket (see sse)
+#[get("/v1/broadcasts/stream")]
+fn stream(sse_port: State<SsePort>) -> HandlerResult<()> {
+    match sse_port.0 {
+        Some(port) => Err(HandlerErrorKind::SseElsewhere(port))?,
+        None => Err(HandlerErrorKind::SseDisabled)?,
+    }
+}
+
+/// Dump the current versions of a broadcaster
+#[get("/v1/broadcasts/<broadcaster_id>", rank = 2)]
+fn get_broadcaster(
+    store: db::Store,
+    reader: HandlerResult<Reader>,
+    broadcaster_id: String,
+    query: HandlerResult<Query<FormatQuery>>,
+) -> HandlerResult<Json> {
+    let reader = reader?;
+    let broadcasts = match query?.0.format.0.unwrap_or(Format::Versions) {
+        Format::Versions => json!(reader.read_broadcaster(&*store, &broadcaster_id)?),
+        Format::Full => json!(reader.read_broadcaster_full(&*store, &broadcaster_id)?),
+    };
     Ok(Json(json!({
         "code": 200,
         "broadcasts": broadcasts
     })))
 }
 
+/// Read the current version of a broadcaster / bchannel
+///
+/// The ETag is the version itself, for use with a PUT's If-Match.
+#[get("/v1/broadcasts/<broadcaster_id>/<bchannel_id>")]
+fn get_broadcast(
+    store: db::Store,
+    reader: HandlerResult<Reader>,
+    broadcaster_id: String,
+    bchannel_id: String,
+    if_none_match: IfNoneMatch,
+    query: HandlerResult<Query<FormatQuery>>,
+) -> HandlerResult<TaggedJson> {
+    let reader = reader?;
+    let format = query?.0.format.0.unwrap_or(Format::Versions);
+    let broadcast = reader.read_broadcast(&*store, &broadcaster_id, &bchannel_id)?;
+    let etag = broadcast.version.clone();
+    if if_none_match.matches(&etag) {
+        return Ok(TaggedJson {
+            etag: Some(etag),
+            json: None,
+        });
+    }
+    let mut broadcasts = HashMap::new();
+    match format {
+        Format::Versions => broadcasts.insert(broadcast.id(), json!(broadcast.version)),
+        Format::Full => broadcasts.insert(broadcast.id(), json!(BroadcastInfo::from(broadcast))),
+    };
+    Ok(TaggedJson {
+        etag: Some(etag),
+        json: Some(Json(json!({
+            "code": 200,
+            "broadcasts": broadcasts
+        }))),
+    })
+}
+
+/// Read a broadcaster / bchannel's history, newest first
+#[get("/v1/broadcasts/<broadcaster_id>/<bchannel_id>/history")]
+fn get_history(
+    store: db::Store,
+    reader: HandlerResult<Reader>,
+    broadcaster_id: String,
+    bchannel_id: String,
+    query: HandlerResult<Query<HistoryQuery>>,
+) -> HandlerResult<Json> {
+    let reader = reader?;
+    let query = query?.0;
+    let limit = query
+        .limit
+        .0
+        .unwrap_or(HISTORY_DEFAULT_LIMIT)
+        .max(1)
+        .min(HISTORY_MAX_LIMIT);
+    let history =
+        reader.read_history(&*store, &broadcaster_id, &bchannel_id, limit, query.before.0)?;
+    // A full page may be followed by older entries
+    let next = if history.len() as i64 == limit {
+        history.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(Json(json!({
+        "code": 200,
+        "history": history,
+        "next": next
+    })))
+}
+
 #[get("/__version__")]
 fn version() -> content::Json<&'static str> {
     content::Json(include_str!("../version.json"))
 }
 
 #[get("/__heartbeat__")]
-fn heartbeat(conn: db::Conn) -> status::Custom<Json> {
-    let (status, db_msg) = match broadcastsv1::table
-        .select(sql::<Integer>("1"))
-        .get_result::<i32>(&*conn)
-    {
-        Ok(_) | Err(DieselError::NotFound) => (Status::Ok, "ok".to_string()),
+fn heartbeat(store: db::Store) -> status::Custom<Json> {
+    let (status, db_msg) = match store.health_check() {
+        Ok(_) => (Status::Ok, "ok".to_string()),
         // XXX: sanitize db_msg
-        Err(e) => (Status::ServiceUnavailable, e.description().to_string()),
+        Err(e) => (Status::ServiceUnavailable, e.to_string()),
     };
 
     status::Custom(
@@ -134,26 +677,57 @@ pub fn rocket() -> Result<Rocket> {
 }
 
 fn setup_rocket(rocket: Rocket) -> Result<Rocket> {
-    let pool = db::pool_from_config(rocket.config())?;
+    let notifier = Arc::new(Notifier::default());
+    let store = Arc::new(NotifyingStore::new(
+        db::store_from_config(rocket.config())?,
+        notifier.clone(),
+    ));
+    // Begin notifying of changes from the current one
+    store.poll()?;
+    notify::spawn_poller_from_config(rocket.config(), &store)?;
+    let store: Arc<BroadcastStore> = store;
+    let sse_address = sse::spawn_from_config(rocket.config(), &store, &notifier)?;
+    websocket::spawn_from_config(rocket.config(), &notifier)?;
+    webhook::spawn_from_config(rocket.config(), &*store, &notifier)?;
     let authenticator = auth::BearerTokenAuthenticator::from_config(rocket.config())?;
+    let validator = Validator::from_config(rocket.config())?;
+    let waiters = Waiters::from_config(rocket.config())?;
+    let sse_port = SsePort(sse_address.map(|address| address.port()));
     let environment = rocket.config().environment;
-    db::run_embedded_migrations(rocket.config())?;
     Ok(rocket
-        .manage(pool)
+        .manage(store)
+        .manage(notifier)
         .manage(authenticator)
+        .manage(validator)
+        .manage(waiters)
+        .manage(sse_port)
         .manage(environment)
         .mount(
             "/",
-            routes![broadcast, get_broadcasts, version, heartbeat, lheartbeat],
+            routes![
+                broadcast,
+                broadcast_many,
+                delete_broadcast,
+                rollback,
+                get_broadcasts,
+                stream,
+                get_broadcaster,
+                get_broadcast,
+                get_history,
+                version,
+                heartbeat,
+                lheartbeat
+            ],
         )
         .catch(errors![not_found]))
 }
 
 #[cfg(test)]
 mod test {
+    use chrono::{Duration, Utc};
     use rocket;
-    use rocket::config::{Config, Environment, RocketConfig};
-    use rocket::http::{Header, Status};
+    use rocket::config::{Config, ConfigBuilder, Environment, RocketConfig};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::Client;
     use rocket::response::Response;
     use serde_json::{self, Value};
@@ -164,8 +738,13 @@ mod test {
     enum Auth {
         Foo,
         FooAlt,
+        /// Restricted to bchannels bar and q*x
+        FooBar,
         Baz,
         Reader,
+        /// Restricted to baz's broadcasts
+        ReaderBaz,
+        Admin,
     }
 
     impl Into<Header<'static>> for Auth {
@@ -173,8 +752,11 @@ mod test {
             let token = match self {
                 Auth::Foo => "feedfacedeadbeef",
                 Auth::FooAlt => "deadbeeffacefeed",
+                Auth::FooBar => "feedfacebaadf00d",
                 Auth::Baz => "baada555deadbeef",
                 Auth::Reader => "00000000deadbeef",
+                Auth::ReaderBaz => "00000000baadf00d",
+                Auth::Admin => "adadadaddeadbeef",
             };
             Header::new("Authorization".to_string(), format!("Bearer {}", token))
         }
@@ -184,30 +766,54 @@ mod test {
     ///
     /// The managed db pool is set to a maxiumum of one connection w/
     /// a transaction began that is never committed
+    ///
+    /// Tests run against an in memory SQLite database when
+    /// ROCKET_DATABASE_URL is undefined
     fn rocket_client() -> Client {
+        client(test_config().unwrap())
+    }
+
+    fn client(config: Config) -> Client {
+        let rocket = setup_rocket(rocket::custom(config, true)).expect("rocket failed");
+        Client::new(rocket).expect("rocket launch failed")
+    }
+
+    fn test_config() -> ConfigBuilder {
         // create a separate test config but inheriting database_url
         let rconfig = RocketConfig::read().expect("reading rocket Config failed");
         let database_url = rconfig
             .active()
             .get_str("database_url")
-            .expect("ROCKET_DATABASE_URL undefined");
+            .unwrap_or("sqlite://:memory:");
 
-        let config = Config::build(Environment::Development)
+        Config::build(Environment::Development)
             .extra("database_url", database_url)
             .extra("database_pool_max_size", 1)
             .extra("database_use_test_transactions", true)
+            .extra("notify_poll_interval", 0)
             .extra(
                 "broadcaster_auth",
                 toml!{
-                    foo = ["feedfacedeadbeef", "deadbeeffacefeed"]
+                    foo = [
+                        "feedfacedeadbeef",
+                        "deadbeeffacefeed",
+                        { token = "feedfacebaadf00d", channels = ["bar", "q*x"] }
+                    ]
                     baz = ["baada555deadbeef"]
                 },
             )
-            .extra("reader_auth", toml!{reader = ["00000000deadbeef"]})
-            .unwrap();
-
-        let rocket = setup_rocket(rocket::custom(config, true)).expect("rocket failed");
-        Client::new(rocket).expect("rocket launch failed")
+            .extra(
+                "reader_auth",
+                toml!{
+                    reader = [
+                        "00000000deadbeef",
+                        { token = "00000000baadf00d", channels = ["baz"] }
+                    ]
+                },
+            )
+            .extra("admin_auth", toml!{admin = ["adadadaddeadbeef"]})
+            .extra("version_max_length", toml!{baz = 8})
+            .extra("max_body_size", 1024)
     }
 
     fn json_body(response: &mut Response) -> Value {
@@ -247,6 +853,136 @@ mod test {
         assert!(result["error"].as_str().unwrap().contains("Version"));
     }
 
+    #[test]
+    fn test_put_invalid() {
+        let client = rocket_client();
+        let long_version = "v".repeat(201);
+        for version in &["v 1", "djE+", &long_version[..]] {
+            let mut response = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(*version)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["code"], 400);
+        }
+        let response = client
+            .put(format!("/v1/broadcasts/foo/{}", "b".repeat(129)))
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        for bchannel_id in &["b%2Fr", "b*r", "b%20r"] {
+            let mut response = client
+                .put(format!("/v1/broadcasts/foo/{}", bchannel_id))
+                .header(Auth::Foo)
+                .body("v1")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let result = json_body(&mut response);
+            assert!(result["error"].as_str().unwrap().contains("bchannel_id"));
+        }
+        // Limited by ROCKET_VERSION_MAX_LENGTH
+        let response = client
+            .put("/v1/broadcasts/baz/bar")
+            .header(Auth::Baz)
+            .body("v12345678")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        for version in &["djE_LQ==", &long_version[1..]] {
+            let response = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(*version)
+                .dispatch();
+            assert!(response.status().class().is_success());
+        }
+        let response = client
+            .put("/v1/broadcasts/baz/bar")
+            .header(Auth::Baz)
+            .body("v1234567")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    #[test]
+    fn test_put_too_large() {
+        let client = rocket_client();
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v".repeat(1025))
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert_eq!(json_body(&mut response)["code"], 413);
+        let body = format!(r#"{{"bar": "v1", "baz": "{}"}}"#, "v".repeat(1024));
+        let response = client
+            .post("/v1/broadcasts/foo")
+            .header(Auth::Foo)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_put_json() {
+        let client = rocket_client();
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(ContentType::JSON)
+            .body(r#"{"version": "v1", "ttl": 60, "note": "Fix a typo"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar?format=full")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let broadcast = &result["broadcasts"]["foo/bar"];
+        assert_eq!(broadcast["version"], "v1");
+        assert_eq!(broadcast["ttl"], 60);
+        assert_eq!(broadcast["note"], "Fix a typo");
+
+        for body in &[
+            r#"{"ttl": 60}"#,
+            r#"{"version": "v2", "ttl": 0}"#,
+            r#"{"version": "v 2"}"#,
+            "v2",
+        ] {
+            let response = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+
+        // A plain text version has no metadata
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v2")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar?format=full")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let broadcast = &result["broadcasts"]["foo/bar"];
+        assert_eq!(broadcast["version"], "v2");
+        assert!(broadcast["ttl"].is_null());
+        assert!(broadcast["note"].is_null());
+    }
+
     #[test]
     fn test_put_no_id() {
         let client = rocket_client();
@@ -292,49 +1028,848 @@ mod test {
     }
 
     #[test]
-    fn test_get_no_auth() {
+    fn test_put_channels() {
         let client = rocket_client();
-        let mut response = client.get("/v1/broadcasts").dispatch();
-        assert_eq!(response.status(), Status::Unauthorized);
-        assert!(
-            response
-                .headers()
-                .get_one("WWW-Authenticate")
-                .unwrap()
-                .starts_with("Bearer ")
-        );
-        let result = json_body(&mut response);
-        assert_eq!(result["code"], 401);
-    }
+        for path in &["/v1/broadcasts/foo/bar", "/v1/broadcasts/foo/quux"] {
+            let response = client.put(*path).header(Auth::FooBar).body("v1").dispatch();
+            assert_eq!(response.status(), Status::Created);
+        }
+        for path in &["/v1/broadcasts/foo/baz", "/v1/broadcasts/foo/quuxy"] {
+            let mut response = client.put(*path).header(Auth::FooBar).body("v1").dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            assert_eq!(json_body(&mut response)["code"], 403);
+        }
+        let response = client
+            .post("/v1/broadcasts/foo")
+            .header(Auth::FooBar)
+            .body(r#"{"bar": "v2", "baz": "v2"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
 
-    #[test]
-    fn test_get_bad_auth() {
-        let client = rocket_client();
-        let mut response = client.get("/v1/broadcasts").header(Auth::Foo).dispatch();
+        let _ = client
+            .put("/v1/broadcasts/foo/baz")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let response = client
+            .delete("/v1/broadcasts/foo/baz")
+            .header(Auth::FooBar)
+            .dispatch();
         assert_eq!(response.status(), Status::Forbidden);
-        let result = json_body(&mut response);
-        assert_eq!(result["code"], 403);
+        let mut response = client
+            .get("/v1/broadcasts/foo")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({
+                "code": 200,
+                "broadcasts": {"foo/bar": "v1", "foo/baz": "v1", "foo/quux": "v1"}
+            })
+        );
     }
 
     #[test]
-    fn test_put_get() {
+    fn test_put_if_match() {
         let client = rocket_client();
-        let _ = client
+        let response = client
             .put("/v1/broadcasts/foo/bar")
-            .header(Auth::FooAlt)
+            .header(Auth::Foo)
+            .header(Header::new("If-Match", "*"))
             .body("v1")
             .dispatch();
+        assert_eq!(response.status(), Status::PreconditionFailed);
         let _ = client
-            .put("/v1/broadcasts/baz/quux")
-            .header(Auth::Baz)
-            .body("v0")
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+
+        let response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert_eq!(etag, r#""v1""#);
+        // Weak ETags never match
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(Header::new("If-Match", format!("W/{}", etag)))
+            .body("v2")
+            .dispatch();
+        assert_eq!(response.status(), Status::PreconditionFailed);
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(Header::new("If-Match", etag.clone()))
+            .body("v2")
             .dispatch();
-        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
         assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response), json!({"code": 200}));
+
+        // A concurrent writer lost the race
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .header(Header::new("If-Match", etag))
+            .body("v3")
+            .dispatch();
+        assert_eq!(response.status(), Status::PreconditionFailed);
+        assert_eq!(json_body(&mut response)["code"], 412);
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
         assert_eq!(
             json_body(&mut response),
-            json!({"code": 200, "broadcasts": {"baz/quux": "v0", "foo/bar": "v1"}})
+            json!({"code": 200, "broadcasts": {"foo/bar": "v2"}})
         );
+
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .header(Header::new("If-Match", "*"))
+            .body("v3")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_post_many() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+
+        let mut response = client
+            .post("/v1/broadcasts/foo")
+            .header(Auth::Foo)
+            .header(ContentType::JSON)
+            .body(r#"{"bar": "v2", "baz": "v0"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"bar": 200, "baz": 201}})
+        );
+        let mut response = client
+            .get("/v1/broadcasts/foo")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v2", "foo/baz": "v0"}})
+        );
+
+        for body in &["", "{}", r#"{"bar": ""}"#, r#"{"bar": 1}"#, r#"["v3"]"#] {
+            let mut response = client
+                .post("/v1/broadcasts/foo")
+                .header(Auth::Foo)
+                .body(*body)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["code"], 400);
+        }
+
+        let response = client
+            .post("/v1/broadcasts/foo")
+            .header(Auth::Baz)
+            .body(r#"{"bar": "v3"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v2"}})
+        );
+    }
+
+    #[test]
+    fn test_get_no_auth() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/broadcasts").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(
+            response
+                .headers()
+                .get_one("WWW-Authenticate")
+                .unwrap()
+                .starts_with("Bearer ")
+        );
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 401);
+    }
+
+    #[test]
+    fn test_get_bad_auth() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/broadcasts").header(Auth::Foo).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 403);
+    }
+
+    #[test]
+    fn test_get_malformed_auth() {
+        let client = rocket_client();
+        for &(credentials, status) in &[
+            ("Basic Zm9vOmJhcg==", Status::Unauthorized),
+            ("Bearer 00000000deadbeeX", Status::Unauthorized),
+            ("Bearer", Status::BadRequest),
+            ("Bearer 00000000 deadbeef", Status::BadRequest),
+        ] {
+            let mut response = client
+                .get("/v1/broadcasts")
+                .header(Header::new("Authorization", credentials))
+                .dispatch();
+            assert_eq!(response.status(), status);
+            assert_eq!(json_body(&mut response)["code"], status.code);
+        }
+        let response = client
+            .get("/v1/broadcasts")
+            .header(Header::new("Authorization", "bearer  00000000deadbeef"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_put_get() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"baz/quux": "v0", "foo/bar": "v1"}})
+        );
+    }
+
+    #[test]
+    fn test_get_etag() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        let response = client
+            .get("/v1/broadcasts?format=full")
+            .header(Auth::Reader)
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_ne!(response.headers().get_one("ETag"), Some(etag.as_str()));
+
+        let mut response = client
+            .get("/v1/broadcasts")
+            .header(Auth::Reader)
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.body().is_none());
+
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v2")
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts")
+            .header(Auth::Reader)
+            .header(Header::new("If-None-Match", format!(r#"W/"x", {}"#, etag)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_ne!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v2"}})
+        );
+    }
+
+    #[test]
+    fn test_get_wait() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        let mut response = client
+            .get("/v1/broadcasts?wait=30&since=stale")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v1"}})
+        );
+
+        let response = client
+            .get(format!(
+                "/v1/broadcasts?wait=1&since={}",
+                etag.trim_matches('"')
+            ))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+
+        for wait in &["bogus", "-1"] {
+            let mut response = client
+                .get(format!("/v1/broadcasts?wait={}&since=stale", wait))
+                .header(Auth::Reader)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["code"], 400);
+        }
+    }
+
+    #[test]
+    fn test_get_wait_limited() {
+        let client = client(test_config().extra("max_waiters", 0).unwrap());
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        // Changed, so no need to wait
+        let response = client
+            .get("/v1/broadcasts?wait=30&since=stale")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        let mut response = client
+            .get(format!(
+                "/v1/broadcasts?wait=30&since={}",
+                etag.trim_matches('"')
+            ))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(json_body(&mut response)["code"], 503);
+    }
+
+    #[test]
+    fn test_get_scoped() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+
+        for path in &["/v1/broadcasts", "/v1/broadcasts?since=0"] {
+            let mut response = client.get(*path).header(Auth::ReaderBaz).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let result = json_body(&mut response);
+            assert_eq!(result["broadcasts"], json!({"baz/quux": "v0"}));
+        }
+        let mut response = client
+            .get("/v1/broadcasts/foo")
+            .header(Auth::ReaderBaz)
+            .dispatch();
+        assert_eq!(json_body(&mut response)["broadcasts"], json!({}));
+        let response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::ReaderBaz)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .get("/v1/broadcasts/foo/bar/history")
+            .header(Auth::ReaderBaz)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .get("/v1/broadcasts/baz/quux")
+            .header(Auth::ReaderBaz)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_get_delta() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts?since=0")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"], json!({"foo/bar": "v1"}));
+        let cursor = result["cursor"].as_i64().unwrap();
+
+        let _ = client
+            .put("/v1/broadcasts/foo/quux")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/foo/quux")
+            .header(Auth::Foo)
+            .body("v2")
+            .dispatch();
+        let _ = client
+            .delete("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .dispatch();
+        let mut response = client
+            .get(format!("/v1/broadcasts?since={}", cursor))
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(
+            result["broadcasts"],
+            json!({"foo/bar": null, "foo/quux": "v2"})
+        );
+        let cursor = result["cursor"].as_i64().unwrap();
+
+        let mut response = client
+            .get(format!("/v1/broadcasts?since={}", cursor))
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"], json!({}));
+        assert_eq!(result["cursor"], cursor);
+
+        let mut response = client
+            .get("/v1/broadcasts?since=2000-01-01T00:00:00Z")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(
+            result["broadcasts"],
+            json!({"foo/bar": null, "foo/quux": "v2"})
+        );
+        assert_eq!(result["cursor"], cursor);
+
+        // Timestamps are UTC
+        for &(hours, ref broadcasts) in &[
+            (-1, json!({"foo/bar": null, "foo/quux": "v2"})),
+            (1, json!({})),
+        ] {
+            let since = Utc::now() + Duration::hours(hours);
+            let mut response = client
+                .get(format!(
+                    "/v1/broadcasts?since={}",
+                    since.format("%Y-%m-%dT%H:%M:%SZ")
+                ))
+                .header(Auth::Reader)
+                .dispatch();
+            assert_eq!(json_body(&mut response)["broadcasts"], *broadcasts);
+        }
+    }
+
+    #[test]
+    fn test_get_full() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v2")
+            .dispatch();
+        for path in &["", "/foo", "/foo/bar"] {
+            let mut response = client
+                .get(format!("/v1/broadcasts{}?format=full", path))
+                .header(Auth::Reader)
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let result = json_body(&mut response);
+            let broadcast = &result["broadcasts"]["foo/bar"];
+            assert_eq!(broadcast["version"], "v2");
+            assert_eq!(broadcast["writer"], "foo");
+            assert!(broadcast["created"].is_string());
+            assert!(broadcast["last_updated"].is_string());
+        }
+
+        // Changing only the metadata changes the ETag
+        let response = client
+            .get("/v1/broadcasts?format=full")
+            .header(Auth::Reader)
+            .dispatch();
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .header(ContentType::JSON)
+            .body(r#"{"version": "v2", "ttl": 60, "note": "Extend"}"#)
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts?format=full")
+            .header(Auth::Reader)
+            .header(Header::new("If-None-Match", etag))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"]["foo/bar"]["note"], "Extend");
+        assert_eq!(result["broadcasts"]["foo/bar"]["ttl"], 60);
+
+        for path in &["/v1/broadcasts?format=bogus", "/v1/broadcasts/foo?format=bogus"] {
+            let mut response = client.get(*path).header(Auth::Reader).dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["code"], 400);
+        }
+    }
+
+    #[test]
+    fn test_get_broadcaster() {
+        let client = rocket_client();
+        for &(path, version) in &[("foo/bar", "v1"), ("foo/quux", "v2")] {
+            let _ = client
+                .put(format!("/v1/broadcasts/{}", path))
+                .header(Auth::Foo)
+                .body(version)
+                .dispatch();
+        }
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+
+        let mut response = client
+            .get("/v1/broadcasts/foo")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v1", "foo/quux": "v2"}})
+        );
+
+        let mut response = client
+            .get("/v1/broadcasts/foo/quux")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/quux": "v2"}})
+        );
+
+        let mut response = client
+            .get("/v1/broadcasts/baz/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json_body(&mut response)["code"], 404);
+
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(json_body(&mut response)["code"], 403);
+    }
+
+    #[test]
+    fn test_stream() {
+        // Served on ROCKET_SSE_PORT (when set) instead
+        let disabled = rocket_client();
+        let enabled = client(test_config().extra("sse_port", 0).unwrap());
+        for &(ref client, error) in &[(disabled, "disabled"), (enabled, "served on port")] {
+            let mut response = client
+                .get("/v1/broadcasts/stream")
+                .header(Auth::Reader)
+                .dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            let result = json_body(&mut response);
+            assert_eq!(result["code"], 404);
+            assert!(result["error"].as_str().unwrap().contains(error));
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let client = rocket_client();
+        for version in &["v0", "v1", "v2"] {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(*version)
+                .dispatch();
+        }
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar/history?limit=2")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        let history = result["history"].as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["version"], "v2");
+        assert_eq!(history[0]["writer"], "foo");
+        assert_eq!(history[1]["version"], "v1");
+
+        let mut response = client
+            .get(format!(
+                "/v1/broadcasts/foo/bar/history?limit=2&before={}",
+                result["next"]
+            ))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        let history = result["history"].as_array().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["version"], "v0");
+        assert!(result["next"].is_null());
+
+        for query in &["limit=bogus", "limit=2&before=-"] {
+            let mut response = client
+                .get(format!("/v1/broadcasts/foo/bar/history?{}", query))
+                .header(Auth::Reader)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["code"], 400);
+        }
+    }
+
+    #[test]
+    fn test_history_bad_auth() {
+        let client = rocket_client();
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar/history")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 403);
+    }
+
+    #[test]
+    fn test_delete() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let mut response = client
+            .delete("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response), json!({"code": 200}));
+
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"baz/quux": "v0"}})
+        );
+
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar/history")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["history"][0]["version"], "v1");
+        assert_eq!(result["history"][0]["deleted"], true);
+
+        let mut response = client
+            .delete("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 404, "error": "Not Found"})
+        );
+    }
+
+    #[test]
+    fn test_delete_bad_auth() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let mut response = client
+            .delete("/v1/broadcasts/foo/bar")
+            .header(Auth::Baz)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(json_body(&mut response)["code"], 403);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let client = rocket_client();
+        for version in &["v0", "v1", "v1"] {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(*version)
+                .dispatch();
+        }
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback")
+            .header(Auth::FooAlt)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "version": "v0"})
+        );
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v0"}})
+        );
+
+        // Rolling back again undoes the rollback
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response)["version"], "v1");
+
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar/history")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let history = result["history"].as_array().unwrap();
+        let first = history.last().unwrap();
+        assert_eq!(first["version"], "v0");
+        let mut response = client
+            .post(format!("/v1/broadcasts/foo/bar/rollback?to={}", first["id"]))
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response)["version"], "v0");
+
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback?to=bogus")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["code"], 400);
+    }
+
+    #[test]
+    fn test_rollback_not_found() {
+        let client = rocket_client();
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json_body(&mut response)["code"], 404);
+
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v0")
+            .dispatch();
+        // No previous version
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json_body(&mut response)["code"], 404);
+
+        let mut response = client
+            .post(format!("/v1/broadcasts/foo/bar/rollback?to={}", i64::max_value()))
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(json_body(&mut response)["code"], 404);
+    }
+
+    #[test]
+    fn test_rollback_bad_auth() {
+        let client = rocket_client();
+        let mut response = client
+            .post("/v1/broadcasts/foo/bar/rollback")
+            .header(Auth::Baz)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(json_body(&mut response)["code"], 403);
+    }
+
+    #[test]
+    fn test_admin() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Admin)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .delete("/v1/broadcasts/baz/quux")
+            .header(Auth::Admin)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let mut response = client.get("/v1/broadcasts").header(Auth::Admin).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            json!({"code": 200, "broadcasts": {"foo/bar": "v1"}})
+        );
+
+        // Admin actions are recorded as theirs
+        for path in &[
+            "/v1/broadcasts/foo/bar/history",
+            "/v1/broadcasts/baz/quux/history",
+        ] {
+            let mut response = client.get(*path).header(Auth::Reader).dispatch();
+            let result = json_body(&mut response);
+            assert_eq!(result["history"][0]["writer"], "admin");
+        }
     }
 
     #[test]
@@ -1,26 +1,56 @@
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
 
+extern crate chrono;
 #[macro_use]
 extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 #[macro_use]
 extern crate failure;
+extern crate hyper;
+extern crate hyper_native_tls;
+#[macro_use]
+extern crate log;
+extern crate ring;
 extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 #[cfg(test)]
 #[macro_use]
 extern crate toml;
+#[cfg(test)]
+extern crate url;
+extern crate ws;
 
 mod auth;
 mod db;
 mod error;
 mod http;
+mod notify;
+mod sse;
+mod validate;
+mod webhook;
+mod websocket;
+
+use std::{env, process};
 
 fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() == 3 && args[1] == "hash-token" {
+        // Print a new token's hash for the rocket Config
+        match auth::hash_token(&args[2]) {
+            Ok(hash) => println!("{}", hash),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     http::rocket().expect("rocket failed").launch();
 }
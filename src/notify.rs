@@ -0,0 +1,410 @@
+/// Notification of broadcast changes to subscribers (e.g. streaming readers)
+///
+/// Changes are read back from the broadcasts' history as they're written,
+/// and the history's also polled every ROCKET_NOTIFY_POLL_INTERVAL seconds
+/// (default 1, 0 to disable) for changes written by other instances sharing
+/// the database.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::err_msg;
+use rocket::config::{Config, ConfigError};
+
+use db::models::{Broadcast, HistoryEntry, NewVersion, Since};
+use db::BroadcastStore;
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+/// A change to a broadcast
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// The change's sequence number (HistoryEntry id)
+    pub sequence: i64,
+    pub broadcaster_id: String,
+    pub bchannel_id: String,
+    /// The new version, None when the broadcast was deleted
+    pub version: Option<String>,
+}
+
+impl Change {
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.broadcaster_id, self.bchannel_id)
+    }
+}
+
+impl From<HistoryEntry> for Change {
+    fn from(entry: HistoryEntry) -> Change {
+        Change {
+            sequence: entry.id,
+            broadcaster_id: entry.broadcaster_id,
+            bchannel_id: entry.bchannel_id,
+            version: if entry.deleted {
+                None
+            } else {
+                Some(entry.version)
+            },
+        }
+    }
+}
+
+/// Determine if a broadcast id matches a subscribed id or glob pattern
+/// (where "*" matches any run of characters)
+pub fn matches(pattern: &str, id: &str) -> bool {
+    let (pattern, id) = (pattern.as_bytes(), id.as_bytes());
+    let (mut p, mut i) = (0, 0);
+    // Where matching resumes from when backtracking: after the last "*" and
+    // the id position it matched up to
+    let mut star = None;
+    while i < id.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, i));
+        } else if p < pattern.len() && pattern[p] == id[i] {
+            p += 1;
+            i += 1;
+        } else if let Some((star_p, star_i)) = star {
+            // Let the last "*" match one more character
+            p = star_p;
+            i = star_i + 1;
+            star = Some((star_p, i));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Fans out Changes to every subscriber
+#[derive(Debug, Default)]
+pub struct Notifier {
+    subscribers: Mutex<Vec<Sender<Change>>>,
+    /// Number of Changes so far, for waiters
+    sequence: Mutex<u64>,
+    changed: Condvar,
+    /// The store's change sequence number as of the last poll
+    polled: Mutex<Option<i64>>,
+}
+
+impl Notifier {
+    /// Receive every subsequent Change
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (tx, rx) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// The current sequence number, to wait for subsequent Changes
+    pub fn sequence(&self) -> u64 {
+        self.sequence.lock().map(|sequence| *sequence).unwrap_or(0)
+    }
+
+    /// Block until a Change after sequence or the timeout elapses
+    ///
+    /// Returns whether a Change occurred.
+    pub fn wait(&self, sequence: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut current = match self.sequence.lock() {
+            Ok(current) => current,
+            Err(_) => return false,
+        };
+        while *current == sequence {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            current = match self.changed.wait_timeout(current, deadline - now) {
+                Ok((current, _)) => current,
+                Err(_) => return false,
+            };
+        }
+        true
+    }
+
+    /// Send a Change to every subscriber, forgetting those that have gone
+    /// away, and wake every waiter
+    pub fn notify(&self, change: &Change) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|tx| tx.send(change.clone()).is_ok());
+        }
+        if let Ok(mut sequence) = self.sequence.lock() {
+            *sequence += 1;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Notify of every change committed to the store since the last poll
+    ///
+    /// The first poll only determines where the next begins.
+    pub fn poll(&self, store: &BroadcastStore) -> HandlerResult<()> {
+        let mut polled = self
+            .polled
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let sequence = store.read_sequence()?;
+        if let Some(since) = *polled {
+            if sequence > since {
+                for entry in store.read_changes(&Since::Sequence(since), sequence)? {
+                    self.notify(&Change::from(entry));
+                }
+            }
+        }
+        *polled = Some(sequence);
+        Ok(())
+    }
+}
+
+/// Limits the number of concurrent streams or waiters, each occupying a
+/// thread
+#[derive(Debug)]
+pub struct Limit {
+    max: usize,
+    active: AtomicUsize,
+}
+
+impl Limit {
+    pub fn new(max: usize) -> Limit {
+        Limit {
+            max: max,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve one of the slots until the returned Reserved is dropped
+    ///
+    /// Err(TooManyConnections) when every slot is taken.
+    pub fn reserve(&self) -> HandlerResult<Reserved> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Err(HandlerErrorKind::TooManyConnections)?
+        }
+        Ok(Reserved(&self.active))
+    }
+}
+
+/// A reserved slot of a Limit, released when dropped
+pub struct Reserved<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Reserved<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A BroadcastStore notifying of every successful write to the store it
+/// wraps
+pub struct NotifyingStore {
+    store: Box<BroadcastStore>,
+    notifier: Arc<Notifier>,
+}
+
+impl NotifyingStore {
+    pub fn new(store: Box<BroadcastStore>, notifier: Arc<Notifier>) -> NotifyingStore {
+        NotifyingStore {
+            store: store,
+            notifier: notifier,
+        }
+    }
+
+    /// Notify of every change committed since the last poll (including
+    /// other instances')
+    pub fn poll(&self) -> HandlerResult<()> {
+        self.notifier.poll(&*self.store)
+    }
+
+    /// Notify of a write just committed, leaving it to the next poll on
+    /// failure
+    fn notify_written(&self) {
+        if let Err(e) = self.poll() {
+            warn!("Error polling for changes: {}", e);
+        }
+    }
+}
+
+/// Poll the store every ROCKET_NOTIFY_POLL_INTERVAL seconds (unless 0) in a
+/// background thread
+pub fn spawn_poller_from_config(config: &Config, store: &Arc<NotifyingStore>) -> Result<()> {
+    let interval = match config.get_int("notify_poll_interval") {
+        Ok(interval) if interval >= 0 => interval as u64,
+        Err(ConfigError::Missing(_)) => 1,
+        _ => Err(err_msg("Invalid ROCKET_NOTIFY_POLL_INTERVAL"))?,
+    };
+    if interval == 0 {
+        return Ok(());
+    }
+    let store = store.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval));
+        if let Err(e) = store.poll() {
+            error!("Error polling for changes: {}", e);
+        }
+    });
+    Ok(())
+}
+
+impl BroadcastStore for NotifyingStore {
+    fn upsert(&self, new: &NewVersion) -> HandlerResult<bool> {
+        let created = self.store.upsert(new)?;
+        self.notify_written();
+        Ok(created)
+    }
+
+    fn upsert_many(&self, new: &[NewVersion]) -> HandlerResult<Vec<bool>> {
+        let created = self.store.upsert_many(new)?;
+        self.notify_written();
+        Ok(created)
+    }
+
+    fn read_all(&self) -> HandlerResult<Vec<Broadcast>> {
+        self.store.read_all()
+    }
+
+    fn read_broadcaster(&self, broadcaster_id: &str) -> HandlerResult<Vec<Broadcast>> {
+        self.store.read_broadcaster(broadcaster_id)
+    }
+
+    fn read_one(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+    ) -> HandlerResult<Option<Broadcast>> {
+        self.store.read_one(broadcaster_id, bchannel_id)
+    }
+
+    fn read_history(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        limit: i64,
+        before: Option<i64>,
+    ) -> HandlerResult<Vec<HistoryEntry>> {
+        self.store
+            .read_history(broadcaster_id, bchannel_id, limit, before)
+    }
+
+    fn read_sequence(&self) -> HandlerResult<i64> {
+        self.store.read_sequence()
+    }
+
+    fn read_changes(&self, since: &Since, until: i64) -> HandlerResult<Vec<HistoryEntry>> {
+        self.store.read_changes(since, until)
+    }
+
+    fn delete(&self, writer: &str, broadcaster_id: &str, bchannel_id: &str) -> HandlerResult<bool> {
+        let deleted = self.store.delete(writer, broadcaster_id, bchannel_id)?;
+        if deleted {
+            self.notify_written();
+        }
+        Ok(deleted)
+    }
+
+    fn health_check(&self) -> Result<()> {
+        self.store.health_check()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use rocket::config::{Config, Environment};
+
+    use super::{matches, Change, Limit, Notifier};
+    use db;
+    use db::models::NewVersion;
+    use error::HandlerErrorKind;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("foo/bar", "foo/bar"));
+        assert!(!matches("foo/bar", "foo/barn"));
+        assert!(!matches("foo/ba", "foo/bar"));
+        assert!(matches("foo/*", "foo/bar"));
+        assert!(!matches("foo/*", "foobar/baz"));
+        assert!(matches("foo/b*", "foo/bar"));
+        assert!(matches("*", "baz/quux"));
+        assert!(matches("foo/settings-*-prod", "foo/settings-main-prod"));
+        assert!(matches("foo/settings-*-prod", "foo/settings--prod"));
+        assert!(!matches("foo/settings-*-prod", "foo/settings-main-prodx"));
+        assert!(!matches("foo/settings-*-prod", "foo/settings-main"));
+        assert!(matches("*/a*b*", "foo/aXbYb"));
+        assert!(!matches("*/a*b", "foo/abc"));
+    }
+
+    #[test]
+    fn test_wait() {
+        let notifier = Arc::new(Notifier::default());
+        let sequence = notifier.sequence();
+        assert!(!notifier.wait(sequence, Duration::from_millis(10)));
+
+        let changes = notifier.subscribe();
+        let writer = notifier.clone();
+        let handle = thread::spawn(move || {
+            writer.notify(&Change {
+                sequence: 1,
+                broadcaster_id: "foo".to_string(),
+                bchannel_id: "bar".to_string(),
+                version: Some("v1".to_string()),
+            })
+        });
+        assert!(notifier.wait(sequence, Duration::from_secs(10)));
+        handle.join().unwrap();
+        assert_eq!(notifier.sequence(), sequence + 1);
+        assert_eq!(changes.recv().unwrap().id(), "foo/bar");
+        // Already changed since sequence
+        assert!(notifier.wait(sequence, Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn test_poll() {
+        let config = Config::build(Environment::Development)
+            .extra("database_url", "memory://")
+            .unwrap();
+        // Written to as if by another instance
+        let store = db::store_from_config(&config).unwrap();
+        let notifier = Notifier::default();
+        notifier.poll(&*store).unwrap();
+
+        let changes = notifier.subscribe();
+        for version in &["v1", "v2"] {
+            store
+                .upsert(&NewVersion {
+                    writer: "foo",
+                    broadcaster_id: "foo",
+                    bchannel_id: "bar",
+                    version: version,
+                    note: None,
+                    ttl: None,
+                    if_match: &[],
+                })
+                .unwrap();
+        }
+        store.delete("foo", "foo", "bar").unwrap();
+        assert!(changes.try_recv().is_err());
+
+        notifier.poll(&*store).unwrap();
+        let versions: Vec<_> = changes.try_iter().map(|change| change.version).collect();
+        assert_eq!(
+            versions,
+            vec![Some("v1".to_string()), Some("v2".to_string()), None]
+        );
+        notifier.poll(&*store).unwrap();
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_limit() {
+        let limit = Limit::new(2);
+        let first = limit.reserve().unwrap();
+        let _second = limit.reserve().unwrap();
+        let err = limit.reserve().err().unwrap();
+        assert_eq!(*err.kind(), HandlerErrorKind::TooManyConnections);
+        drop(first);
+        assert!(limit.reserve().is_ok());
+    }
+}
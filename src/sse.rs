@@ -0,0 +1,373 @@
+/// Server-Sent Events streaming of broadcast changes
+///
+/// Served on ROCKET_SSE_PORT (when defined) rather than by rocket, as every
+/// stream would hold one of rocket's few workers (and rocket doesn't flush
+/// responses until 8KB of them are written). Each stream is served by its own
+/// thread, up to ROCKET_SSE_MAX_STREAMS (default 256) at once: further
+/// streams are rejected with a 503. Rocket instead responds to the path
+/// with a 404 naming the port.
+///
+/// Readers GET /v1/broadcasts/stream with their Bearer token (in the
+/// Authorization header) for a snapshot of the current broadcasts followed
+/// by an event per change (within the reader's scope, with a null version
+/// when the broadcast was deleted):
+///
+///     event: snapshot
+///     data: {"foo/bar":"v1"}
+///
+///     event: change
+///     data: {"foo/bar":"v2"}
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::err_msg;
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rocket::config::{Config, ConfigError, Environment};
+use rocket::http::Status;
+use serde::Serialize;
+use serde_json;
+
+use auth::BearerTokenAuthenticator;
+use db::models::Reader;
+use db::BroadcastStore;
+use error::{HandlerError, HandlerErrorKind, HandlerResult, Result};
+use notify::{Change, Limit, Notifier, Reserved};
+
+const PATH: &str = "/v1/broadcasts/stream";
+
+/// Default maximum number of concurrent streams
+const DEFAULT_MAX_STREAMS: i64 = 256;
+
+/// Threads beyond the maximum number of streams, to reject requests with
+const SPARE_THREADS: usize = 2;
+
+/// Seconds between keepalive comments, which also detect disconnected
+/// clients
+const KEEPALIVE_INTERVAL: u64 = 15;
+
+/// Seconds to wait on a client to accept a write before disconnecting it
+const WRITE_TIMEOUT: u64 = 30;
+
+/// Serve Server-Sent Events on ROCKET_SSE_PORT (when defined) in background
+/// threads
+///
+/// Returns the address served on.
+pub fn spawn_from_config(
+    config: &Config,
+    store: &Arc<BroadcastStore>,
+    notifier: &Arc<Notifier>,
+) -> Result<Option<SocketAddr>> {
+    let port = match config.get_int("sse_port") {
+        Ok(port) => port,
+        Err(ConfigError::Missing(_)) => return Ok(None),
+        Err(_) => Err(err_msg("Invalid ROCKET_SSE_PORT"))?,
+    };
+    let max_streams = match config.get_int("sse_max_streams") {
+        Ok(max_streams) if max_streams > 0 => max_streams as usize,
+        Err(ConfigError::Missing(_)) => DEFAULT_MAX_STREAMS as usize,
+        _ => Err(err_msg("Invalid ROCKET_SSE_MAX_STREAMS"))?,
+    };
+    let streams = Streams {
+        store: store.clone(),
+        notifier: notifier.clone(),
+        authenticator: BearerTokenAuthenticator::from_config(config)?,
+        environment: config.environment,
+        limit: Limit::new(max_streams),
+    };
+
+    let address = format!("{}:{}", config.address, port);
+    let mut server = Server::http(&address[..])?;
+    server.keep_alive(None);
+    server.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT)));
+    let mut listening = server.handle_threads(streams, max_streams + SPARE_THREADS)?;
+    let address = listening.socket;
+    // Detach from (rather than join when dropped) the serving threads
+    listening.close()?;
+    Ok(Some(address))
+}
+
+/// Serves the streams
+struct Streams {
+    store: Arc<BroadcastStore>,
+    notifier: Arc<Notifier>,
+    authenticator: BearerTokenAuthenticator,
+    environment: Environment,
+    limit: Limit,
+}
+
+impl Handler for Streams {
+    fn handle(&self, request: Request, response: Response<Fresh>) {
+        // Errors are from clients that have gone away
+        let _ = match self.open(&request) {
+            Ok(stream) => stream.serve(response),
+            Err(e) => respond_error(response, &e, self.environment),
+        };
+    }
+}
+
+impl Streams {
+    /// Authorize a request, reserving one of the streams for it
+    fn open(&self, request: &Request) -> HandlerResult<Stream> {
+        let path = match request.uri {
+            RequestUri::AbsolutePath(ref path) => path.splitn(2, '?').next().unwrap_or(""),
+            _ => "",
+        };
+        if request.method != Method::Get || path != PATH {
+            Err(HandlerErrorKind::NotFound)?
+        }
+        let credentials = request
+            .headers
+            .get_raw("Authorization")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .ok_or(HandlerErrorKind::MissingAuth)?;
+        let reader = self.authenticator.authorize_reader(credentials)?;
+
+        let reserved = self.limit.reserve()?;
+        // Subscribe before reading the snapshot so no change is missed
+        let changes = self.notifier.subscribe();
+        let snapshot = reader.read_broadcasts(&*self.store)?;
+        Ok(Stream {
+            _reserved: reserved,
+            reader: reader,
+            snapshot: snapshot,
+            changes: changes,
+        })
+    }
+}
+
+/// A text/event-stream response of a snapshot of the current broadcasts
+/// followed by an event per Change (within the reader's scope)
+struct Stream<'a> {
+    _reserved: Reserved<'a>,
+    reader: Reader,
+    snapshot: HashMap<String, String>,
+    changes: Receiver<Change>,
+}
+
+impl<'a> Stream<'a> {
+    /// Write the stream until the client goes away
+    fn serve(self, mut response: Response<Fresh>) -> io::Result<()> {
+        response
+            .headers_mut()
+            .set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+        response
+            .headers_mut()
+            .set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+        let mut body = response.start()?;
+        write_events(&mut body, &self.reader, &self.snapshot, &self.changes)?;
+        body.end()
+    }
+}
+
+/// Render a HandlerError's JSON response (as its rocket Responder does)
+fn respond_error(
+    mut response: Response<Fresh>,
+    error: &HandlerError,
+    environment: Environment,
+) -> io::Result<()> {
+    let status = error.kind().http_status();
+    *response.status_mut() = StatusCode::from_u16(status.code);
+    response
+        .headers_mut()
+        .set_raw("Content-Type", vec![b"application/json".to_vec()]);
+    if status == Status::Unauthorized {
+        response.headers_mut().set_raw(
+            "WWW-Authenticate",
+            vec![format!(r#"Bearer realm="{}""#, environment).into_bytes()],
+        );
+    }
+    let body = json!({
+        "code": status.code,
+        "error": format!("{}", error)
+    });
+    response.send(body.to_string().as_bytes())
+}
+
+/// Write the snapshot then every Change (within the reader's scope) as it
+/// occurs, flushing each event
+///
+/// Returns once the changes end, or with an error once the client's gone.
+fn write_events<W: Write>(
+    out: &mut W,
+    reader: &Reader,
+    snapshot: &HashMap<String, String>,
+    changes: &Receiver<Change>,
+) -> io::Result<()> {
+    out.write_all(&event("snapshot", snapshot))?;
+    out.flush()?;
+    loop {
+        let timeout = Duration::from_secs(KEEPALIVE_INTERVAL);
+        match changes.recv_timeout(timeout) {
+            Ok(ref change) if !reader.can_read(&change.id()) => continue,
+            Ok(change) => {
+                let mut broadcasts = HashMap::new();
+                broadcasts.insert(change.id(), change.version);
+                out.write_all(&event("change", &broadcasts))?;
+            }
+            Err(RecvTimeoutError::Timeout) => out.write_all(b":keepalive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        out.flush()?;
+    }
+}
+
+/// Render an event with a JSON payload
+fn event<T: Serialize>(name: &str, data: &HashMap<String, T>) -> Vec<u8> {
+    let data = serde_json::to_string(data).expect("broadcasts serialize");
+    format!("event: {}\ndata: {}\n\n", name, data).into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::str;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rocket::config::{Config, Environment};
+
+    use super::{spawn_from_config, write_events};
+    use db::models::{NewVersion, Reader};
+    use db::{self, BroadcastStore};
+    use notify::{Change, Notifier, NotifyingStore};
+
+    fn new_version<'a>(version: &'a str) -> NewVersion<'a> {
+        NewVersion {
+            writer: "foo",
+            broadcaster_id: "foo",
+            bchannel_id: "bar",
+            version: version,
+            note: None,
+            ttl: None,
+            if_match: &[],
+        }
+    }
+
+    /// Request the stream
+    fn get(address: &SocketAddr, token: Option<&str>) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let authorization = token.map_or("".to_string(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        write!(
+            stream,
+            "GET /v1/broadcasts/stream HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            authorization
+        ).unwrap();
+        stream
+    }
+
+    /// Read from the stream until it includes expected (or ends)
+    fn read_until(stream: &mut TcpStream, expected: &str) -> String {
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&received).contains(expected) {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                len => received.extend_from_slice(&buf[..len]),
+            }
+        }
+        String::from_utf8(received).unwrap()
+    }
+
+    #[test]
+    fn test_stream() {
+        let config = Config::build(Environment::Development)
+            .address("127.0.0.1")
+            .extra("database_url", "memory://")
+            .extra("broadcaster_auth", toml!{foo = ["f00f00"]})
+            .extra(
+                "reader_auth",
+                toml!{reader = ["deadbeef", { token = "baadf00d", channels = ["baz"] }]},
+            )
+            .extra("sse_port", 0)
+            .extra("sse_max_streams", 1)
+            .unwrap();
+        let notifier = Arc::new(Notifier::default());
+        let store: Arc<BroadcastStore> = Arc::new(NotifyingStore::new(
+            db::store_from_config(&config).unwrap(),
+            notifier.clone(),
+        ));
+        let address = spawn_from_config(&config, &store, &notifier)
+            .unwrap()
+            .unwrap();
+        store.upsert(&new_version("v1")).unwrap();
+
+        let mut stream = get(&address, Some("deadbeef"));
+        let response = read_until(&mut stream, "event: snapshot\ndata: {\"foo/bar\":\"v1\"}\n\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/event-stream\r\n"));
+
+        // The only stream's taken
+        let response = read_until(&mut get(&address, Some("baadf00d")), "\"code\":503");
+        assert!(response.starts_with("HTTP/1.1 503 "));
+
+        store.upsert(&new_version("v2")).unwrap();
+        read_until(&mut stream, "event: change\ndata: {\"foo/bar\":\"v2\"}\n\n");
+    }
+
+    #[test]
+    fn test_stream_bad_auth() {
+        let config = Config::build(Environment::Development)
+            .address("127.0.0.1")
+            .extra("database_url", "memory://")
+            .extra("broadcaster_auth", toml!{foo = ["f00f00"]})
+            .extra("reader_auth", toml!{reader = ["deadbeef"]})
+            .extra("sse_port", 0)
+            .unwrap();
+        let notifier = Arc::new(Notifier::default());
+        let store: Arc<BroadcastStore> = Arc::from(db::store_from_config(&config).unwrap());
+        let address = spawn_from_config(&config, &store, &notifier)
+            .unwrap()
+            .unwrap();
+
+        let response = read_until(&mut get(&address, None), "\"code\":401");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+        assert!(response.contains("WWW-Authenticate: Bearer realm=\"development\"\r\n"));
+        let response = read_until(&mut get(&address, Some("f00f00")), "\"code\":403");
+        assert!(response.starts_with("HTTP/1.1 403 "));
+    }
+
+    #[test]
+    fn test_events() {
+        let (tx, rx) = channel();
+        let mut snapshot = HashMap::new();
+        snapshot.insert("foo/bar".to_string(), "v1".to_string());
+        let reader = Reader::new("otto".to_string(), Some(vec!["foo/*".to_string()]));
+        // baz/bar is outside of the reader's scope
+        for (sequence, broadcaster_id) in (1..).zip(&["baz", "foo"]) {
+            tx.send(Change {
+                sequence: sequence,
+                broadcaster_id: broadcaster_id.to_string(),
+                bchannel_id: "bar".to_string(),
+                version: None,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let mut body = Vec::new();
+        write_events(&mut body, &reader, &snapshot, &rx).unwrap();
+        assert_eq!(
+            str::from_utf8(&body).unwrap(),
+            "event: snapshot\ndata: {\"foo/bar\":\"v1\"}\n\n\
+             event: change\ndata: {\"foo/bar\":null}\n\n"
+        );
+    }
+}
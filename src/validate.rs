@@ -0,0 +1,238 @@
+/// Validation of new broadcasts before they're written to the store
+///
+/// Versions must be URL safe Base 64 (optionally padded), no longer than the
+/// broadcastsv1.version column or a shorter maximum configured per
+/// broadcaster in ROCKET_VERSION_MAX_LENGTH, e.g.:
+///
+///     version_max_length = { kinto = 64 }
+///
+/// broadcaster_ids and bchannel_ids are limited to their columns' lengths, as
+/// are versions' notes. Their ttls must be positive. broadcaster_ids
+/// colliding with the Server-Sent Events stream's path ("stream") are
+/// reserved.
+///
+/// Request bodies are limited to ROCKET_MAX_BODY_SIZE bytes.
+use std::collections::HashMap;
+
+use rocket::config::{Config, ConfigError};
+
+use error::{HandlerErrorKind, HandlerResult, Result};
+
+/// Lengths of the broadcastsv1 columns, in characters (as VARCHARs are
+/// measured)
+pub const MAX_BROADCASTER_ID_LENGTH: usize = 64;
+pub const MAX_BCHANNEL_ID_LENGTH: usize = 128;
+pub const MAX_VERSION_LENGTH: usize = 200;
+pub const MAX_NOTE_LENGTH: usize = 1000;
+
+/// broadcaster_ids shadowed by other /v1/broadcasts/ paths (GET
+/// /v1/broadcasts/stream points readers at the Server-Sent Events port)
+pub const RESERVED_BROADCASTER_IDS: &[&str] = &["stream"];
+
+/// Default maximum request body size in bytes
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub struct Validator {
+    /// Version maximum lengths keyed by broadcaster_id
+    version_max_lengths: HashMap<String, usize>,
+    /// Maximum request body size in bytes
+    pub max_body_size: u64,
+}
+
+impl Validator {
+    pub fn from_config(config: &Config) -> Result<Validator> {
+        let max_body_size = match config.get_int("max_body_size") {
+            Ok(size) if size > 0 => size as u64,
+            Err(ConfigError::Missing(_)) => DEFAULT_MAX_BODY_SIZE,
+            _ => Err(format_err!("Invalid ROCKET_MAX_BODY_SIZE"))?,
+        };
+        Ok(Validator {
+            version_max_lengths: version_max_lengths_from_config(config)?,
+            max_body_size: max_body_size,
+        })
+    }
+
+    /// Validate a new broadcast's ids and version
+    pub fn validate(
+        &self,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        version: &str,
+    ) -> HandlerResult<()> {
+        if !valid_id(broadcaster_id, MAX_BROADCASTER_ID_LENGTH)
+            || RESERVED_BROADCASTER_IDS.contains(&broadcaster_id)
+            || !valid_id(bchannel_id, MAX_BCHANNEL_ID_LENGTH)
+        {
+            Err(HandlerErrorKind::InvalidIdError)?
+        }
+        let max_length = self
+            .version_max_lengths
+            .get(broadcaster_id)
+            .cloned()
+            .unwrap_or(MAX_VERSION_LENGTH);
+        // Base 64 is ASCII, so its length in bytes is in characters
+        if !is_base64url(version) || version.len() > max_length {
+            Err(HandlerErrorKind::InvalidVersionDataError)?
+        }
+        Ok(())
+    }
+}
+
+/// Validate a new version's metadata
+pub fn validate_metadata(note: Option<&str>, ttl: Option<i32>) -> HandlerResult<()> {
+    if note.map_or(false, |note| note.chars().count() > MAX_NOTE_LENGTH)
+        || ttl.map_or(false, |ttl| ttl <= 0)
+    {
+        Err(HandlerErrorKind::InvalidMetadataError)?
+    }
+    Ok(())
+}
+
+/// Load the per broadcaster version maximum lengths
+fn version_max_lengths_from_config(config: &Config) -> Result<HashMap<String, usize>> {
+    let mut version_max_lengths = HashMap::new();
+    let table = match config.get_table("version_max_length") {
+        Ok(table) => table,
+        Err(ConfigError::Missing(_)) => return Ok(version_max_lengths),
+        Err(_) => Err(format_err!("Invalid ROCKET_VERSION_MAX_LENGTH"))?,
+    };
+    for (broadcaster_id, value) in table {
+        let max_length = match value.as_integer() {
+            Some(length) if length > 0 && length as usize <= MAX_VERSION_LENGTH => length as usize,
+            _ => Err(format_err!(
+                "Invalid ROCKET_VERSION_MAX_LENGTH for: {:?} (must be 1 to {})",
+                broadcaster_id,
+                MAX_VERSION_LENGTH
+            ))?,
+        };
+        version_max_lengths.insert(broadcaster_id.to_string(), max_length);
+    }
+    Ok(version_max_lengths)
+}
+
+/// Determine if an id is 1 to max_length of A-Z, a-z, 0-9, "_", "." or "-"
+///
+/// So ids never contain the "/" separating a broadcast id's broadcaster_id
+/// from its bchannel_id, nor the "*" of glob patterns.
+fn valid_id(id: &str, max_length: usize) -> bool {
+    // ASCII, so its length in bytes is in characters
+    !id.is_empty()
+        && id.len() <= max_length
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+/// Determine if a string is non-empty URL safe Base 64 (with optional "="
+/// padding)
+fn is_base64url(value: &str) -> bool {
+    let unpadded = value.trim_right_matches('=');
+    !unpadded.is_empty()
+        && value.len() - unpadded.len() <= 2
+        && unpadded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+
+    use super::{
+        is_base64url, valid_id, validate_metadata, Validator, DEFAULT_MAX_BODY_SIZE,
+        MAX_NOTE_LENGTH, MAX_VERSION_LENGTH,
+    };
+    use error::HandlerErrorKind::{InvalidIdError, InvalidVersionDataError};
+
+    #[test]
+    fn test_is_base64url() {
+        assert!(is_base64url("v1"));
+        assert!(is_base64url("aGVsbG8-_w=="));
+        assert!(!is_base64url("aGVsbG8==="));
+        assert!(!is_base64url("a+b/c"));
+        assert!(!is_base64url("v 1"));
+        assert!(!is_base64url("v=1"));
+        assert!(!is_base64url(""));
+        assert!(!is_base64url("="));
+        assert!(!is_base64url("=="));
+    }
+
+    #[test]
+    fn test_valid_id() {
+        assert!(valid_id("foo", 3));
+        assert!(valid_id("Foo_1.2-3", 9));
+        assert!(!valid_id("", 3));
+        assert!(!valid_id("fooo", 3));
+        for id in &["foo/bar", "foo*", "foo bar", "foo%2F", "f\u{f6}o", "\u{1f4e3}"] {
+            assert!(!valid_id(id, 64), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::build(Environment::Development)
+            .extra("version_max_length", toml!{baz = 4})
+            .unwrap();
+        let validator = Validator::from_config(&config).unwrap();
+
+        assert!(validator.validate("foo", "bar", "v1").is_ok());
+        let version = "v".repeat(MAX_VERSION_LENGTH);
+        assert!(validator.validate("foo", "bar", &version).is_ok());
+        assert!(validator.validate("baz", "bar", "v123").is_ok());
+        for &(broadcaster_id, bchannel_id, version, ref kind) in &[
+            ("foo", "bar", "", InvalidVersionDataError),
+            ("foo", "bar", "v.1", InvalidVersionDataError),
+            ("baz", "bar", "v1234", InvalidVersionDataError),
+            ("foo", "bar", "==", InvalidVersionDataError),
+            ("foo", "", "v1", InvalidIdError),
+            ("foo", "b/r", "v1", InvalidIdError),
+            ("f*", "bar", "v1", InvalidIdError),
+            (&"f".repeat(65), "bar", "v1", InvalidIdError),
+            ("foo", &"b".repeat(129), "v1", InvalidIdError),
+            ("stream", "bar", "v1", InvalidIdError),
+        ] {
+            let err = validator
+                .validate(broadcaster_id, bchannel_id, version)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), kind);
+        }
+        let err = validator
+            .validate("foo", "bar", &format!("{}1", version))
+            .err()
+            .unwrap();
+        assert_eq!(*err.kind(), InvalidVersionDataError);
+    }
+
+    #[test]
+    fn test_validate_metadata() {
+        assert!(validate_metadata(None, None).is_ok());
+        let note = "n".repeat(MAX_NOTE_LENGTH);
+        assert!(validate_metadata(Some(&note), Some(60)).is_ok());
+        assert!(validate_metadata(Some(&format!("{}n", note)), None).is_err());
+        assert!(validate_metadata(None, Some(0)).is_err());
+        assert!(validate_metadata(None, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for version_max_length in &[toml!{baz = 0}, toml!{baz = 201}, toml!{baz = "8"}] {
+            let config = Config::build(Environment::Development)
+                .extra("version_max_length", version_max_length.clone())
+                .unwrap();
+            assert!(Validator::from_config(&config).is_err());
+        }
+        for max_body_size in &[0, -1] {
+            let config = Config::build(Environment::Development)
+                .extra("max_body_size", *max_body_size)
+                .unwrap();
+            assert!(Validator::from_config(&config).is_err());
+        }
+        let config = Config::build(Environment::Development).unwrap();
+        assert_eq!(
+            Validator::from_config(&config).unwrap().max_body_size,
+            DEFAULT_MAX_BODY_SIZE
+        );
+    }
+}
@@ -0,0 +1,660 @@
+/// Outbound webhooks notifying of broadcast changes
+///
+/// Configured as an array of tables in ROCKET_WEBHOOKS, e.g. in Rocket.toml:
+///
+///     [[global.webhooks]]
+///     url = "https://example.com/megaphone"
+///     # (optional) broadcast ids or glob patterns
+///     channels = ["foo/*"]
+///     secret = "s3cr3t"
+///
+/// Changes are POSTed as JSON (with a null version when the broadcast was
+/// deleted), along with the change's sequence number (as an id) and when it
+/// was enqueued (in Unix time):
+///
+///     {"broadcasts": {"foo/bar": "v2"}, "id": 42, "timestamp": 1500000000}
+///
+/// signed by an X-Megaphone-Signature header of "t=<Unix time of the
+/// attempt>,sha256=<hex HMAC-SHA256 of "<t>.<body>" keyed by the secret>".
+/// Receivers should reject stale signatures and ids already received (as
+/// deliveries may be retried) so they can't be replayed.
+///
+/// Each webhook's deliveries are attempted in order from its own thread, so
+/// slow webhooks don't delay others'. Failed deliveries are retried with
+/// exponential backoff.
+///
+/// Pending deliveries are persisted to ROCKET_WEBHOOK_QUEUE_PATH (when
+/// defined, at most every second) to survive restarts, along with the
+/// sequence number of the last change enqueued: the changes since then (e.g.
+/// those committed just before a crash) are read back from the broadcasts'
+/// history upon restart. So deliveries may be repeated after a crash.
+///
+/// Instances sharing a database are notified of each other's changes, so
+/// webhooks should only be configured on one of them.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use failure::err_msg;
+use hyper::header::{ContentType, Headers};
+use hyper::net::HttpsConnector;
+use hyper::Client;
+use hyper_native_tls::NativeTlsClient;
+use ring::{digest, hmac};
+use rocket::config::{Config, ConfigError, Value};
+use serde_json;
+
+use auth::to_hex;
+use db::models::Since;
+use db::BroadcastStore;
+use error::Result;
+use notify::{matches, Change, Notifier};
+
+/// Delivery attempts before a delivery is dropped
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Maximum seconds between delivery attempts
+const MAX_BACKOFF: i64 = 3600;
+
+/// Seconds to wait on a webhook's response
+const TIMEOUT: u64 = 10;
+
+/// Seconds between writes of the queue file (when it's changed)
+const PERSIST_INTERVAL: u64 = 1;
+
+/// A webhook subscription
+struct Webhook {
+    url: String,
+    /// Broadcast ids or patterns
+    channels: Vec<String>,
+    secret: String,
+}
+
+impl Webhook {
+    fn from_value(value: &Value) -> Result<Webhook> {
+        let table = value
+            .as_table()
+            .ok_or_else(|| err_msg("Invalid ROCKET_WEBHOOKS entry"))?;
+        let string = |name: &str| {
+            table
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format_err!("Invalid or undefined ROCKET_WEBHOOKS {}", name))
+        };
+        let channels = match table.get("channels") {
+            Some(channels) => channels
+                .as_array()
+                .and_then(|channels| {
+                    channels
+                        .iter()
+                        .map(|channel| channel.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| err_msg("Invalid ROCKET_WEBHOOKS channels"))?,
+            None => vec!["*".to_string()],
+        };
+        Ok(Webhook {
+            url: string("url")?,
+            channels: channels,
+            secret: string("secret")?,
+        })
+    }
+
+    fn subscribed(&self, id: &str) -> bool {
+        self.channels.iter().any(|channel| matches(channel, id))
+    }
+}
+
+/// A pending delivery of a payload to a webhook
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Delivery {
+    url: String,
+    payload: String,
+    attempts: u32,
+    /// Unix timestamp of when to next attempt delivery
+    not_before: i64,
+}
+
+/// The (persisted) contents of the Queue
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+struct Pending {
+    /// Sequence number of the last Change enqueued
+    sequence: Option<i64>,
+    /// Pending deliveries, oldest first
+    deliveries: Vec<Delivery>,
+}
+
+struct Queue {
+    pending: Mutex<Pending>,
+    changed: Condvar,
+    /// Where deliveries are persisted
+    path: Option<PathBuf>,
+    /// Whether pending has changed since it was last persisted
+    dirty: AtomicBool,
+}
+
+impl Queue {
+    /// Create the queue, loading its persisted deliveries if any exist
+    fn load(path: Option<PathBuf>) -> Result<Queue> {
+        let pending = match path {
+            Some(ref path) if path.exists() => {
+                serde_json::from_reader(BufReader::new(File::open(path)?))?
+            }
+            _ => Pending::default(),
+        };
+        Ok(Queue {
+            pending: Mutex::new(pending),
+            changed: Condvar::new(),
+            path: path,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<Pending> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Enqueue a Change's deliveries, unless it already was
+    fn enqueue(&self, webhooks: &[Webhook], change: &Change) {
+        let mut pending = self.lock();
+        if pending
+            .sequence
+            .map_or(false, |sequence| change.sequence <= sequence)
+        {
+            return;
+        }
+        pending.deliveries.extend(deliveries(webhooks, change));
+        pending.sequence = Some(change.sequence);
+        self.mark_dirty();
+        self.changed.notify_all();
+    }
+
+    /// Enqueue every Change committed since the last one enqueued (or when
+    /// the queue's new, begin from the current one)
+    fn catch_up(&self, webhooks: &[Webhook], store: &BroadcastStore) -> Result<()> {
+        let sequence = store.read_sequence()?;
+        let since = self.lock().sequence;
+        match since {
+            Some(since) => {
+                for entry in store.read_changes(&Since::Sequence(since), sequence)? {
+                    self.enqueue(webhooks, &Change::from(entry));
+                }
+            }
+            None => {
+                self.lock().sequence = Some(sequence);
+                self.mark_dirty();
+            }
+        }
+        Ok(())
+    }
+
+    /// Note the contents of the queue changed, to be persisted by the next
+    /// flush
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Persist the contents of the queue, if they changed since the last
+    /// flush
+    ///
+    /// Only serializing them holds the lock, not the write.
+    fn flush(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = serde_json::to_vec(&*self.lock())
+            .map_err(Into::into)
+            .and_then(|json| write_queue(&json, path));
+        if result.is_err() {
+            self.mark_dirty();
+        }
+        result
+    }
+}
+
+/// Atomically replace the queue file
+fn write_queue(json: &[u8], path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(json)?;
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// POST Changes to the webhooks in ROCKET_WEBHOOKS (when defined) from
+/// background threads
+pub fn spawn_from_config(
+    config: &Config,
+    store: &BroadcastStore,
+    notifier: &Notifier,
+) -> Result<()> {
+    let values = match config.get_slice("webhooks") {
+        Ok(values) => values,
+        Err(ConfigError::Missing(_)) => return Ok(()),
+        Err(_) => Err(err_msg("Invalid ROCKET_WEBHOOKS"))?,
+    };
+    let mut webhooks = Vec::new();
+    let mut urls = HashSet::new();
+    for value in values {
+        let webhook = Webhook::from_value(value)?;
+        if !urls.insert(webhook.url.clone()) {
+            Err(format_err!(
+                "Invalid ROCKET_WEBHOOKS dupe url: {}",
+                webhook.url
+            ))?
+        }
+        webhooks.push(webhook);
+    }
+    let path = config.get_str("webhook_queue_path").ok().map(PathBuf::from);
+    let queue = Queue::load(path)?;
+
+    let mut client = Client::with_connector(HttpsConnector::new(NativeTlsClient::new()?));
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT)));
+    // Subscribe before catching up so no change is missed
+    let changes = notifier.subscribe();
+    queue.catch_up(&webhooks, store)?;
+    spawn(webhooks, queue, client, changes)
+}
+
+fn spawn(
+    webhooks: Vec<Webhook>,
+    queue: Queue,
+    client: Client,
+    changes: Receiver<Change>,
+) -> Result<()> {
+    // Forget deliveries to webhooks no longer configured
+    queue
+        .lock()
+        .deliveries
+        .retain(|delivery| webhooks.iter().any(|webhook| webhook.url == delivery.url));
+    queue.mark_dirty();
+    queue.flush()?;
+
+    let webhooks = Arc::new(webhooks);
+    let queue = Arc::new(queue);
+    let client = Arc::new(client);
+
+    if queue.path.is_some() {
+        let queue = queue.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(PERSIST_INTERVAL));
+            if let Err(e) = queue.flush() {
+                error!("Error writing webhook queue: {}", e);
+            }
+        });
+    }
+
+    for index in 0..webhooks.len() {
+        let (queue, webhooks, client) = (queue.clone(), webhooks.clone(), client.clone());
+        thread::spawn(move || deliver(&queue, &webhooks[index], &client));
+    }
+
+    thread::spawn(move || {
+        for change in changes.iter() {
+            queue.enqueue(&webhooks, &change);
+        }
+    });
+    Ok(())
+}
+
+/// A Delivery of a Change to each of its subscribed webhooks
+fn deliveries(webhooks: &[Webhook], change: &Change) -> Vec<Delivery> {
+    let id = change.id();
+    let mut broadcasts = HashMap::new();
+    broadcasts.insert(id.clone(), change.version.clone());
+    let now = Utc::now().timestamp();
+    let payload = json!({
+        "broadcasts": broadcasts,
+        "id": change.sequence,
+        "timestamp": now
+    }).to_string();
+    webhooks
+        .iter()
+        .filter(|webhook| webhook.subscribed(&id))
+        .map(|webhook| Delivery {
+            url: webhook.url.clone(),
+            payload: payload.clone(),
+            attempts: 0,
+            not_before: now,
+        })
+        .collect()
+}
+
+/// Attempt a webhook's deliveries as they become due, forever
+fn deliver(queue: &Queue, webhook: &Webhook, client: &Client) {
+    let mut pending = queue.lock();
+    loop {
+        let now = Utc::now().timestamp();
+        let index = match next(&pending.deliveries, &webhook.url, now) {
+            Ok(index) => index,
+            Err(Some(due)) => {
+                let timeout = Duration::from_secs((due - now) as u64);
+                pending = match queue.changed.wait_timeout(pending, timeout) {
+                    Ok((pending, _)) => pending,
+                    Err(poisoned) => poisoned.into_inner().0,
+                };
+                continue;
+            }
+            Err(None) => {
+                pending = queue
+                    .changed
+                    .wait(pending)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                continue;
+            }
+        };
+        let delivery = pending.deliveries[index].clone();
+        drop(pending);
+
+        let delivered = match post(client, webhook, &delivery.payload) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Error delivering webhook to {}: {}", webhook.url, e);
+                false
+            }
+        };
+
+        // Only this thread removes the webhook's deliveries, so it's still
+        // the first of them (though other deliveries may have moved it)
+        pending = queue.lock();
+        let index = pending
+            .deliveries
+            .iter()
+            .position(|queued| queued.url == webhook.url)
+            .expect("delivery is pending");
+        if delivered {
+            pending.deliveries.remove(index);
+        } else if delivery.attempts + 1 >= MAX_ATTEMPTS {
+            error!(
+                "Dropping webhook delivery to {} after {} attempts",
+                webhook.url, MAX_ATTEMPTS
+            );
+            pending.deliveries.remove(index);
+        } else {
+            let delivery = &mut pending.deliveries[index];
+            delivery.attempts += 1;
+            delivery.not_before = Utc::now().timestamp() + backoff(delivery.attempts);
+        }
+        queue.mark_dirty();
+    }
+}
+
+/// Index of a webhook's next delivery (its first, keeping its deliveries in
+/// order) if it's due
+///
+/// Otherwise when it's due (if there is one).
+fn next(
+    deliveries: &[Delivery],
+    url: &str,
+    now: i64,
+) -> ::std::result::Result<usize, Option<i64>> {
+    match deliveries.iter().position(|delivery| delivery.url == url) {
+        Some(index) if deliveries[index].not_before <= now => Ok(index),
+        Some(index) => Err(Some(deliveries[index].not_before)),
+        None => Err(None),
+    }
+}
+
+/// Seconds to wait before a delivery's next attempt
+fn backoff(attempts: u32) -> i64 {
+    2i64.pow(attempts).min(MAX_BACKOFF)
+}
+
+fn post(client: &Client, webhook: &Webhook, payload: &str) -> Result<()> {
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    let timestamp = Utc::now().timestamp();
+    headers.set_raw(
+        "X-Megaphone-Signature",
+        vec![sign(&webhook.secret, timestamp, payload).into_bytes()],
+    );
+    let response = client
+        .post(&webhook.url)
+        .headers(headers)
+        .body(payload)
+        .send()?;
+    if !response.status.is_success() {
+        Err(format_err!(
+            "Unexpected response status: {}",
+            response.status
+        ))?
+    }
+    Ok(())
+}
+
+/// The X-Megaphone-Signature of a payload sent at timestamp
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let key = hmac::SigningKey::new(&digest::SHA256, secret.as_bytes());
+    let signature = hmac::sign(&key, format!("{}.{}", timestamp, payload).as_bytes());
+    format!("t={},sha256={}", timestamp, to_hex(signature.as_ref()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper::Client;
+    use rocket::config::{Config, Environment};
+    use serde_json::{self, Value};
+
+    use super::{backoff, next, sign, spawn, Delivery, Queue, Webhook, MAX_BACKOFF};
+    use db::models::NewVersion;
+    use db::{self, BroadcastStore};
+    use notify::{Change, Notifier};
+
+    fn delivery(url: &str, not_before: i64) -> Delivery {
+        Delivery {
+            url: url.to_string(),
+            payload: "{}".to_string(),
+            attempts: 0,
+            not_before: not_before,
+        }
+    }
+
+    #[test]
+    fn test_next() {
+        assert_eq!(next(&[], "http://a", 10), Err(None));
+        let deliveries = vec![
+            delivery("http://a", 20),
+            delivery("http://a", 0),
+            delivery("http://b", 15),
+            delivery("http://c", 5),
+        ];
+        assert_eq!(next(&deliveries, "http://a", 10), Err(Some(20)));
+        assert_eq!(next(&deliveries, "http://a", 20), Ok(0));
+        assert_eq!(next(&deliveries, "http://b", 10), Err(Some(15)));
+        assert_eq!(next(&deliveries, "http://c", 10), Ok(3));
+        assert_eq!(next(&deliveries, "http://d", 10), Err(None));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), 2);
+        assert_eq!(backoff(5), 32);
+        assert_eq!(backoff(20), MAX_BACKOFF);
+    }
+
+    fn webhook(url: &str, channel: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            channels: vec![channel.to_string()],
+            secret: "s3cr3t".to_string(),
+        }
+    }
+
+    fn change(sequence: i64, broadcaster_id: &str, version: &str) -> Change {
+        Change {
+            sequence: sequence,
+            broadcaster_id: broadcaster_id.to_string(),
+            bchannel_id: "bar".to_string(),
+            version: Some(version.to_string()),
+        }
+    }
+
+    /// The versions of foo/bar delivered by each delivery
+    fn versions(deliveries: &[Delivery]) -> Vec<Value> {
+        deliveries
+            .iter()
+            .map(|delivery| {
+                let payload: Value = serde_json::from_str(&delivery.payload).unwrap();
+                payload["broadcasts"]["foo/bar"].clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_persist() {
+        let path = env::temp_dir().join("megaphone_test_webhook_queue.json");
+        let _ = fs::remove_file(&path);
+        let webhooks = vec![webhook("http://a", "foo/*"), webhook("http://b", "*")];
+        let queue = Queue::load(Some(path.clone())).unwrap();
+        queue.enqueue(&webhooks, &change(1, "foo", "v1"));
+        queue.enqueue(&webhooks, &change(2, "baz", "v1"));
+        // Already enqueued
+        queue.enqueue(&webhooks, &change(1, "foo", "v1"));
+        queue.flush().unwrap();
+
+        let queue = Queue::load(Some(path.clone())).unwrap();
+        let pending = queue.lock();
+        assert_eq!(pending.sequence, Some(2));
+        let urls: Vec<_> = pending
+            .deliveries
+            .iter()
+            .map(|delivery| delivery.url.as_str())
+            .collect();
+        assert_eq!(urls, vec!["http://a", "http://b", "http://b"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_catch_up() {
+        let path = env::temp_dir().join("megaphone_test_webhook_catch_up.json");
+        let _ = fs::remove_file(&path);
+        let config = Config::build(Environment::Development)
+            .extra("database_url", "memory://")
+            .unwrap();
+        let store = db::store_from_config(&config).unwrap();
+        let upsert = |store: &BroadcastStore, version| {
+            store
+                .upsert(&NewVersion {
+                    writer: "foo",
+                    broadcaster_id: "foo",
+                    bchannel_id: "bar",
+                    version: version,
+                    note: None,
+                    ttl: None,
+                    if_match: &[],
+                })
+                .unwrap();
+        };
+        let webhooks = vec![webhook("http://a", "*")];
+        upsert(&*store, "v1");
+        // A new queue begins from the current change
+        let queue = Queue::load(Some(path.clone())).unwrap();
+        queue.catch_up(&webhooks, &*store).unwrap();
+        queue.flush().unwrap();
+        assert!(queue.lock().deliveries.is_empty());
+
+        // Committed but never enqueued (e.g. megaphone crashed in between)
+        upsert(&*store, "v2");
+        upsert(&*store, "v3");
+        let queue = Queue::load(Some(path.clone())).unwrap();
+        queue.catch_up(&webhooks, &*store).unwrap();
+        queue.flush().unwrap();
+        assert_eq!(versions(&queue.lock().deliveries), vec!["v2", "v3"]);
+
+        let persisted = Queue::load(Some(path.clone())).unwrap();
+        assert_eq!(*persisted.lock(), *queue.lock());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deliver() {
+        // A stub webhook recording the requests it receives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push(line.trim().to_lowercase());
+                }
+                let length = headers
+                    .iter()
+                    .filter_map(|header| header.split("content-length: ").nth(1))
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                tx.send((headers, String::from_utf8(body).unwrap()))
+                    .unwrap();
+            }
+        });
+
+        // A stub webhook that never responds
+        let unresponsive = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unresponsive_url = format!("http://{}/hook", unresponsive.local_addr().unwrap());
+        thread::spawn(move || unresponsive.incoming().collect::<Vec<_>>());
+
+        let notifier = Notifier::default();
+        spawn(
+            vec![webhook(&unresponsive_url, "*"), webhook(&url, "foo/*")],
+            Queue::load(None).unwrap(),
+            Client::new(),
+            notifier.subscribe(),
+        ).unwrap();
+        notifier.notify(&change(1, "baz", "v0"));
+        notifier.notify(&change(2, "foo", "v1"));
+
+        let (headers, body) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["broadcasts"], json!({"foo/bar": "v1"}));
+        assert_eq!(payload["id"], 2);
+        assert!(payload["timestamp"].as_i64().unwrap() > 0);
+        assert!(headers.starts_with(&["post /hook http/1.1".to_string()]));
+        let signature = headers
+            .iter()
+            .filter_map(|header| header.split("x-megaphone-signature: ").nth(1))
+            .next()
+            .unwrap();
+        let timestamp = signature[2..signature.find(',').unwrap()].parse().unwrap();
+        assert_eq!(signature, sign("s3cr3t", timestamp, &body));
+    }
+}
@@ -0,0 +1,389 @@
+/// WebSocket subscriptions to broadcast changes
+///
+/// Readers authenticate the handshake with their Bearer token (in the
+/// Authorization header) then send subscribe messages listing broadcast ids,
+/// or glob patterns (where "*" matches any run of characters):
+///
+///     {"subscribe": ["foo/bar", "baz/*"]}
+///
+/// Changes to subscribed broadcasts (within the reader's scope) are sent as
+/// (with a null version when the broadcast was deleted):
+///
+///     {"broadcasts": {"foo/bar": "v2"}}
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::err_msg;
+use rocket::config::{Config, ConfigError};
+use serde_json;
+use ws::{self, CloseCode, Handler, Message, Request, Response, Sender, WebSocket};
+
+use auth::BearerTokenAuthenticator;
+use db::models::Reader;
+use error::{HandlerErrorKind, Result};
+use notify::{matches, Change, Notifier};
+
+/// A subscribe message
+#[derive(Deserialize)]
+struct Subscribe {
+    subscribe: Vec<String>,
+}
+
+/// A connection's subscription
+struct Subscriber {
+    out: Sender,
+    reader: Reader,
+    /// Broadcast ids or patterns
+    patterns: Vec<String>,
+}
+
+impl Subscriber {
+    fn subscribed(&self, id: &str) -> bool {
+        self.reader.can_read(id) && self.patterns.iter().any(|pattern| matches(pattern, id))
+    }
+}
+
+/// Subscribers keyed by connection id
+type Subscribers = Arc<Mutex<HashMap<u32, Subscriber>>>;
+
+/// Serve WebSocket subscriptions on ROCKET_WEBSOCKET_PORT (when defined) in
+/// background threads
+///
+/// Returns the address served on.
+pub fn spawn_from_config(config: &Config, notifier: &Notifier) -> Result<Option<SocketAddr>> {
+    let port = match config.get_int("websocket_port") {
+        Ok(port) => port,
+        Err(ConfigError::Missing(_)) => return Ok(None),
+        Err(_) => Err(err_msg("Invalid ROCKET_WEBSOCKET_PORT"))?,
+    };
+    let address = format!("{}:{}", config.address, port);
+    let authenticator = BearerTokenAuthenticator::from_config(config)?;
+    let (address, _) = spawn(address, authenticator, notifier)?;
+    Ok(Some(address))
+}
+
+/// Serve WebSocket subscriptions on address, returning the address bound to
+/// (once it is) and the subscribers
+fn spawn(
+    address: String,
+    authenticator: BearerTokenAuthenticator,
+    notifier: &Notifier,
+) -> Result<(SocketAddr, Subscribers)> {
+    let authenticator = Arc::new(authenticator);
+    let subscribers = Subscribers::default();
+
+    let changes = notifier.subscribe();
+
+    // Bind from the serving thread (its WebSocket isn't Send), reporting
+    // back whether it could
+    let (bound_tx, bound_rx) = channel();
+    let (served, connected) = (address.clone(), subscribers.clone());
+    thread::spawn(move || {
+        let socket = WebSocket::new(move |out| Connection {
+            out: out,
+            reader: None,
+            authenticator: authenticator.clone(),
+            subscribers: connected.clone(),
+        }).and_then(|socket| socket.bind(&served[..]));
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                let _ = bound_tx.send(Err(e.to_string()));
+                return;
+            }
+        };
+        let _ = bound_tx.send(socket.local_addr().map_err(|e| e.to_string()));
+        if let Err(e) = socket.run() {
+            error!("Error serving WebSockets on {}: {}", served, e);
+        }
+    });
+    let bound = bound_rx
+        .recv()
+        .unwrap_or_else(|_| Err("binding panicked".to_string()))
+        .map_err(|e| format_err!("Error binding WebSockets to {}: {}", address, e))?;
+
+    let notified = subscribers.clone();
+    thread::spawn(move || {
+        for change in changes.iter() {
+            notify(&notified, &change);
+        }
+    });
+    Ok((bound, subscribers))
+}
+
+/// Send a Change to its subscribers
+fn notify(subscribers: &Mutex<HashMap<u32, Subscriber>>, change: &Change) {
+    let id = change.id();
+    let mut broadcasts = HashMap::new();
+    broadcasts.insert(id.clone(), change.version.clone());
+    let message = json!({ "broadcasts": broadcasts }).to_string();
+    if let Ok(subscribers) = subscribers.lock() {
+        for subscriber in subscribers.values().filter(|sub| sub.subscribed(&id)) {
+            // Failed connections are closed and forgotten via on_close
+            let _ = subscriber.out.send(message.as_str());
+        }
+    }
+}
+
+/// A reader's WebSocket connection
+struct Connection {
+    out: Sender,
+    /// The reader, once the handshake's authorized
+    reader: Option<Reader>,
+    authenticator: Arc<BearerTokenAuthenticator>,
+    subscribers: Subscribers,
+}
+
+impl Handler for Connection {
+    fn on_request(&mut self, request: &Request) -> ws::Result<Response> {
+        let credentials = request
+            .header("Authorization")
+            .and_then(|value| str::from_utf8(value).ok());
+        let authorized = match credentials {
+            Some(credentials) => self.authenticator.authorize_reader(credentials),
+            None => Err(HandlerErrorKind::MissingAuth.into()),
+        };
+        match authorized {
+            Ok(reader) => {
+                self.reader = Some(reader);
+                Response::from_request(request)
+            }
+            Err(e) => {
+                let status = e.kind().http_status();
+                Ok(Response::new(
+                    status.code,
+                    status.reason,
+                    e.to_string().into_bytes(),
+                ))
+            }
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let subscribe = msg
+            .as_text()
+            .ok()
+            .and_then(|text| serde_json::from_str::<Subscribe>(text).ok());
+        let subscribe = match subscribe {
+            Some(subscribe) => subscribe,
+            None => {
+                return self
+                    .out
+                    .close_with_reason(CloseCode::Invalid, "Invalid subscribe message")
+            }
+        };
+        let reader = match self.reader {
+            Some(ref reader) => reader,
+            // Unauthorized handshakes are rejected, so unreachable
+            None => return self.out.close(CloseCode::Policy),
+        };
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers
+                .entry(self.out.connection_id())
+                .or_insert_with(|| Subscriber {
+                    out: self.out.clone(),
+                    reader: reader.clone(),
+                    patterns: Vec::new(),
+                })
+                .patterns
+                .extend(subscribe.subscribe);
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.remove(&self.out.connection_id());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use rocket::config::{Config, Environment};
+    use url::Url;
+    use ws::{self, CloseCode, Handler, Handshake, Message, Request, Sender};
+
+    use super::{spawn, Subscriber, Subscribers};
+    use auth::BearerTokenAuthenticator;
+    use notify::{Change, Notifier};
+
+    fn spawn_test() -> (Notifier, SocketAddr, Subscribers) {
+        let config = Config::build(Environment::Development)
+            .extra("broadcaster_auth", toml!{foo = ["f00f00"]})
+            .extra(
+                "reader_auth",
+                toml!{reader = ["deadbeef", { token = "baadf00d", channels = ["baz"] }]},
+            )
+            .unwrap();
+        let authenticator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        let notifier = Notifier::default();
+        let (address, subscribers) =
+            spawn("127.0.0.1:0".to_string(), authenticator, &notifier).unwrap();
+        (notifier, address, subscribers)
+    }
+
+    /// A client subscribing (with a reader's token) upon connecting, which
+    /// forwards the first message it receives then closes
+    struct Client {
+        out: Sender,
+        token: &'static str,
+        subscribe: &'static str,
+        received: mpsc::Sender<String>,
+    }
+
+    impl Handler for Client {
+        fn build_request(&mut self, url: &Url) -> ws::Result<Request> {
+            let mut request = Request::from_url(url)?;
+            request.headers_mut().push((
+                "Authorization".to_string(),
+                format!("Bearer {}", self.token).into_bytes(),
+            ));
+            Ok(request)
+        }
+
+        fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
+            self.out.send(self.subscribe)
+        }
+
+        fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+            let _ = self.received.send(msg.into_text()?);
+            self.out.close(CloseCode::Normal)
+        }
+
+        fn on_close(&mut self, code: CloseCode, _: &str) {
+            let _ = self.received.send(format!("closed: {:?}", code));
+        }
+    }
+
+    /// Connect a Client in a background thread, returning what it receives
+    fn connect(
+        address: SocketAddr,
+        token: &'static str,
+        subscribe: &'static str,
+    ) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            ws::connect(format!("ws://{}", address), |out| Client {
+                out: out,
+                token: token,
+                subscribe: subscribe,
+                received: tx.clone(),
+            })
+        });
+        rx
+    }
+
+    /// Wait for the subscribers to satisfy condition
+    fn wait_for<F>(subscribers: &Subscribers, condition: F)
+    where
+        F: Fn(&HashMap<u32, Subscriber>) -> bool,
+    {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition(&subscribers.lock().unwrap()) {
+            assert!(Instant::now() < deadline, "Timed out waiting on subscribers");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The status line of a handshake's response
+    fn handshake_status(address: SocketAddr, token: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let authorization = token.map_or("".to_string(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            authorization
+        ).unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.windows(2).any(|window| window == b"\r\n") {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                len => response.extend_from_slice(&buf[..len]),
+            }
+        }
+        let response = String::from_utf8_lossy(&response).into_owned();
+        response.lines().next().unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (_notifier, address, _) = spawn_test();
+        assert!(handshake_status(address, None).starts_with("HTTP/1.1 401 "));
+        assert!(handshake_status(address, Some("f00f00")).starts_with("HTTP/1.1 403 "));
+        assert!(handshake_status(address, Some("deadbeef")).starts_with("HTTP/1.1 101 "));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let (notifier, address, subscribers) = spawn_test();
+        // Scoped to baz/*
+        let received = connect(address, "baadf00d", r#"{"subscribe": ["foo/bar", "baz/q*"]}"#);
+        wait_for(&subscribers, |subscribers| {
+            subscribers
+                .values()
+                .any(|subscriber| !subscriber.patterns.is_empty())
+        });
+
+        // Outside of the reader's scope, then unsubscribed
+        for &(broadcaster_id, bchannel_id) in &[("foo", "bar"), ("baz", "bar"), ("baz", "quux")] {
+            notifier.notify(&Change {
+                sequence: 1,
+                broadcaster_id: broadcaster_id.to_string(),
+                bchannel_id: bchannel_id.to_string(),
+                version: Some("v1".to_string()),
+            });
+        }
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            r#"{"broadcasts":{"baz/quux":"v1"}}"#
+        );
+        assert_eq!(received.recv_timeout(timeout).unwrap(), "closed: Normal");
+        // Forgotten once closed
+        wait_for(&subscribers, |subscribers| subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_invalid() {
+        let (_notifier, address, subscribers) = spawn_test();
+        for subscribe in &["foo/bar", r#"{"subscribe": "foo/bar"}"#] {
+            let received = connect(address, "deadbeef", subscribe);
+            assert_eq!(
+                received.recv_timeout(Duration::from_secs(10)).unwrap(),
+                "closed: Invalid"
+            );
+        }
+        wait_for(&subscribers, |subscribers| subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_bind_error() {
+        let (notifier, address, _) = spawn_test();
+        let config = Config::build(Environment::Development)
+            .extra("broadcaster_auth", toml!{foo = ["f00f00"]})
+            .extra("reader_auth", toml!{reader = ["deadbeef"]})
+            .unwrap();
+        let authenticator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        assert!(spawn(address.to_string(), authenticator, &notifier).is_err());
+    }
+}